@@ -1,5 +1,8 @@
 use crate::*;
 
+// every transfer takes GAS_FOR_FT_TRANSFER
+const MAX_PAYOUTS_PER_FLUSH: u64 = 20;
+
 impl Contract {
     pub(crate) fn internal_add_balance(&mut self, account_id: &AccountId, value: Balance) {
         if value > 0 {
@@ -12,6 +15,7 @@ impl Contract {
 #[near_bindgen]
 impl Contract{
     pub fn withdraw(&mut self, amount: Option<U128>) -> Promise {
+        assert!(!self.pause_state.withdrawals, "Withdrawals are paused");
         let account_id = env::predecessor_account_id();
         let balance: Balance = self.internal_balances.get(&account_id).unwrap_or(&0u128).clone();
 
@@ -27,7 +31,38 @@ impl Contract{
         self.internal_balances
             .insert(account_id.clone(), balance - amount);
 
+        // nothing left for flush_payouts
+        if balance == amount {
+            self.payout_queue.remove(&account_id);
+        }
+
         self.internal_ft_transfer(&account_id, amount)
     }
 
+    // send queued seller payouts, anyone can call it. Accounts without balance are removed from the queue
+    pub fn flush_payouts(&mut self, limit: Option<u64>) -> PromiseOrValue<()> {
+        assert!(!self.pause_state.withdrawals, "Withdrawals are paused");
+        let limit = limit.unwrap_or(MAX_PAYOUTS_PER_FLUSH);
+        assert!(limit > 0 && limit <= MAX_PAYOUTS_PER_FLUSH, "Illegal limit");
+
+        let account_ids: Vec<AccountId> = self.payout_queue.iter().take(limit as usize).collect();
+
+        let mut payouts: Vec<(AccountId, Balance)> = Vec::new();
+        for account_id in account_ids {
+            self.payout_queue.remove(&account_id);
+
+            let balance: Balance = self.internal_balances.get(&account_id).unwrap_or(&0u128).clone();
+            if balance > 0 {
+                self.internal_balances.insert(account_id.clone(), 0);
+                payouts.push((account_id, balance));
+            }
+        }
+
+        if payouts.is_empty() {
+            return PromiseOrValue::Value(());
+        }
+
+        self.internal_ft_transfer_batch(payouts).into()
+    }
+
 }
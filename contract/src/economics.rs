@@ -0,0 +1,134 @@
+use crate::*;
+
+// token or group parameters which replace the global ones, None keeps the global value
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Default)]
+#[borsh(crate = "near_sdk::borsh")]
+#[serde(crate = "near_sdk::serde")]
+pub struct EconomicsOverride {
+    pub min_mint_price: Option<U128>,
+    pub price_curve: Option<PriceCurve>,
+    pub seller_fee: Option<FeeFraction>,
+    pub referral_1_fee: Option<FeeFraction>,
+    pub referral_2_fee: Option<FeeFraction>,
+    pub sale_cooldown: Option<U64>,
+}
+
+// parameters used to trade a given token
+#[derive(Serialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TokenEconomics {
+    pub group: Option<String>,
+    pub min_mint_price: U128,
+    pub price_curve: PriceCurve,
+    pub seller_fee: FeeFraction,
+    pub referral_1_fee: FeeFraction,
+    pub referral_2_fee: FeeFraction,
+    pub sale_cooldown: U64,
+}
+
+impl EconomicsOverride {
+    pub fn assert_valid(&self) {
+        if let Some(price_curve) = &self.price_curve {
+            price_curve.assert_valid();
+        }
+        for fee in [&self.seller_fee, &self.referral_1_fee, &self.referral_2_fee].iter().copied().flatten() {
+            fee.assert_valid();
+        }
+    }
+}
+
+impl TokenEconomics {
+    pub fn apply(&mut self, economics_override: &EconomicsOverride) {
+        if let Some(min_mint_price) = economics_override.min_mint_price {
+            self.min_mint_price = min_mint_price;
+        }
+        if let Some(price_curve) = &economics_override.price_curve {
+            self.price_curve = price_curve.clone();
+        }
+        if let Some(seller_fee) = &economics_override.seller_fee {
+            self.seller_fee = seller_fee.clone();
+        }
+        if let Some(referral_1_fee) = &economics_override.referral_1_fee {
+            self.referral_1_fee = referral_1_fee.clone();
+        }
+        if let Some(referral_2_fee) = &economics_override.referral_2_fee {
+            self.referral_2_fee = referral_2_fee.clone();
+        }
+        if let Some(sale_cooldown) = economics_override.sale_cooldown {
+            self.sale_cooldown = sale_cooldown;
+        }
+    }
+
+    pub fn fees_overflow(&self) -> bool {
+        fees_overflow(vec![&self.seller_fee, &self.referral_1_fee, &self.referral_2_fee])
+    }
+
+    pub fn assert_fees_overflow(&self) {
+        assert!(!self.fees_overflow(), "Fees overflow");
+    }
+
+    // a change which overflows the fees is skipped
+    pub fn apply_if_valid(&mut self, economics_override: &EconomicsOverride) {
+        let mut economics = self.clone();
+        economics.apply(economics_override);
+        if !economics.fees_overflow() {
+            *self = economics;
+        }
+    }
+}
+
+impl Contract {
+    pub(crate) fn get_global_economics(&self) -> TokenEconomics {
+        TokenEconomics {
+            group: None,
+            min_mint_price: U128(self.min_mint_price),
+            price_curve: self.price_curve.clone(),
+            seller_fee: self.seller_fee.clone(),
+            referral_1_fee: self.referral_1_fee.clone(),
+            referral_2_fee: self.referral_2_fee.clone(),
+            sale_cooldown: U64(self.sale_cooldown),
+        }
+    }
+
+    // global parameters, overridden by the token group and then by the token itself
+    pub(crate) fn internal_get_token_economics(&self, token_id: &TokenId) -> TokenEconomics {
+        self.resolve_economics(Some(token_id), None, &self.get_effective_changes())
+    }
+
+    // stored parameters with the given changes applied in order. The group is taken from the token if token_id is set
+    pub(crate) fn resolve_economics(&self, token_id: Option<&TokenId>, group: Option<&String>, changes: &[&ParameterChange]) -> TokenEconomics {
+        let mut economics = self.get_global_economics();
+        let mut group: Option<String> = token_id.map_or(group.cloned(), |token_id| self.token_groups.get(token_id).cloned());
+        let mut token_override: Option<EconomicsOverride> = token_id.and_then(|token_id| self.token_economics.get(token_id).cloned());
+
+        for change in changes {
+            match change {
+                ParameterChange::Global(economics_override) => economics.apply_if_valid(economics_override),
+                ParameterChange::TokenGroup { token_id: changed_id, group: new_group } if Some(changed_id) == token_id => {
+                    group = new_group.clone();
+                }
+                ParameterChange::TokenEconomics { token_id: changed_id, economics: new_override } if Some(changed_id) == token_id => {
+                    token_override = new_override.clone();
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(group) = group {
+            let group_override = changes.iter().fold(self.group_economics.get(&group), |group_override, change| match change {
+                ParameterChange::GroupEconomics { group: changed_group, economics: new_override } if changed_group == &group => new_override.clone(),
+                _ => group_override,
+            });
+            if let Some(group_override) = group_override {
+                economics.apply(&group_override);
+            }
+            economics.group = Some(group);
+        }
+
+        if let Some(token_override) = token_override {
+            economics.apply(&token_override);
+        }
+
+        economics
+    }
+}
@@ -1,8 +1,10 @@
 use crate::*;
+use crate::market::PurchaseError;
 
 pub mod emit {
     use super::*;
     use near_sdk::serde_json::json;
+    use near_contract_standards::non_fungible_token::events::{NftBurn, NftMint};
 
     #[derive(Serialize)]
     #[serde(crate = "near_sdk::serde")]
@@ -39,6 +41,15 @@ pub mod emit {
         pub amount: Balance,
     }
 
+    #[derive(Serialize)]
+    #[serde(crate = "near_sdk::serde")]
+    struct PurchaseRejectedData<'a> {
+        pub account_id: &'a AccountId,
+        #[serde(with = "u128_dec_format")]
+        pub amount: Balance,
+        pub reason: &'a PurchaseError,
+    }
+
     fn log_event<T: Serialize>(event: &str, data: T) {
         let event = json!({
             "standard": "nftinder",
@@ -66,6 +77,10 @@ pub mod emit {
         log_event("deposit", AccountAmountData { account_id, amount });
     }
 
+    pub fn add_purchase_rejected(account_id: &AccountId, amount: Balance, reason: &PurchaseError) {
+        log_event("purchase_rejected", PurchaseRejectedData { account_id, amount, reason });
+    }
+
     pub fn add_storage(account_id: &AccountId, amount: Balance) {
         log_event("storage", AccountAmountData { account_id, amount });
     }
@@ -81,6 +96,15 @@ pub mod emit {
     pub fn add_burn_nft(account_id: &AccountId, token_id: &TokenId) {
         log_event("nft_burn", AccountTokenData { account_id, token_id });
     }
+
+    // NEP-171 events for wallets and indexers
+    pub fn add_nep171_mint(owner_id: &AccountId, token_id: &TokenId) {
+        NftMint { owner_id, token_ids: &[token_id], memo: None }.emit();
+    }
+
+    pub fn add_nep171_burn(owner_id: &AccountId, token_id: &TokenId, authorized_id: Option<&AccountId>) {
+        NftBurn { owner_id, token_ids: &[token_id], authorized_id: authorized_id.map(|account_id| account_id.as_ref()), memo: None }.emit();
+    }
 }
 
 pub mod u128_dec_format {
@@ -1,11 +1,14 @@
 use crate::*;
+use crate::market::PurchaseError;
 use near_contract_standards::fungible_token::core::ext_ft_core;
 use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
 use near_sdk::json_types::U128;
 use near_sdk::{serde_json, Promise, PromiseOrValue, PromiseResult};
+use std::collections::HashMap;
 
 pub const GAS_FOR_FT_TRANSFER: Gas = Gas::from_tgas(10);
 pub const GAS_FOR_AFTER_FT_TRANSFER: Gas = Gas::from_tgas(10);
+pub const GAS_FOR_AFTER_FT_TRANSFER_BATCH: Gas = Gas::from_tgas(15);
 
 #[ext_contract(ext_ft_contract)]
 trait ExtFtContract {
@@ -15,6 +18,8 @@ trait ExtFtContract {
 #[ext_contract(ext_self)]
 pub trait ExtContract {
     fn callback_after_withdraw(&mut self, sender_id: AccountId, amount: U128);
+    fn callback_after_payouts(&mut self, payouts: Vec<(AccountId, U128)>);
+    fn nft_resolve_transfer(&mut self, previous_owner_id: AccountId, receiver_id: AccountId, token_id: TokenId, approved_account_ids: Option<HashMap<AccountId, u64>>) -> bool;
 }
 
 #[derive(Deserialize)]
@@ -25,16 +30,29 @@ pub enum TokenReceiverMsg {
         message: String,
         signature: String
     },
+    // purchases are processed in order, the unused deposit is refunded
+    BatchPurchase {
+        purchases: Vec<SignedPurchase>
+    },
     Storage {
         index: StoragePackageIndex
     }
 }
 
+#[derive(Deserialize)]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug, Serialize))]
+#[serde(crate = "near_sdk::serde")]
+pub struct SignedPurchase {
+    message: String,
+    signature: String
+}
+
 #[near_bindgen]
 impl FungibleTokenReceiver for Contract {
     fn ft_on_transfer(&mut self, sender_id: AccountId, amount: U128, msg: String) -> PromiseOrValue<U128> {
         let token_id = env::predecessor_account_id();
         assert_eq!(self.ft_account_id, token_id, "Wrong token");
+        self.internal_apply_pending_changes();
 
         let amount = amount.0;
 
@@ -43,16 +61,39 @@ impl FungibleTokenReceiver for Contract {
             TokenReceiverMsg::Purchase { message, signature } => {
                 events::emit::add_deposit(&sender_id, amount);
 
-                self.nft_mint(message, signature, sender_id, amount);
+                match self.nft_mint(message, signature, sender_id.clone(), amount) {
+                    Ok(used_amount) => PromiseOrValue::Value(U128(amount - used_amount)),
+                    Err(error) => {
+                        events::emit::add_purchase_rejected(&sender_id, amount, &error);
+
+                        PromiseOrValue::Value(U128(amount))
+                    }
+                }
+            },
+            TokenReceiverMsg::BatchPurchase { purchases } => {
+                events::emit::add_deposit(&sender_id, amount);
+
+                let mut unused_amount = amount;
+                for SignedPurchase { message, signature } in purchases {
+                    match self.nft_mint(message, signature, sender_id.clone(), unused_amount) {
+                        Ok(used_amount) => unused_amount -= used_amount,
+                        Err(error) => events::emit::add_purchase_rejected(&sender_id, unused_amount, &error)
+                    }
+                }
 
-                PromiseOrValue::Value(U128(0))
+                PromiseOrValue::Value(U128(unused_amount))
             },
             TokenReceiverMsg::Storage { index } => {
+                if self.pause_state.storage {
+                    events::emit::add_purchase_rejected(&sender_id, amount, &PurchaseError::Paused);
+                    return PromiseOrValue::Value(U128(amount));
+                }
+
                 events::emit::add_storage(&sender_id, amount);
 
-                self.buy_storage(sender_id, amount, index);
+                let used_amount = self.buy_storage(sender_id, amount, index);
 
-                PromiseOrValue::Value(U128(0))
+                PromiseOrValue::Value(U128(amount - used_amount))
             }
         }
     }
@@ -75,6 +116,22 @@ impl Contract {
         };
     }
 
+    #[private]
+    pub fn callback_after_payouts(&mut self, payouts: Vec<(AccountId, U128)>) {
+        assert_eq!(env::promise_results_count(), payouts.len() as u64, "Err: expected a promise result for every payout");
+        for (index, (account_id, amount)) in payouts.into_iter().enumerate() {
+            match env::promise_result(index as u64) {
+                PromiseResult::Successful(_) => {
+                    events::emit::add_withdraw_succeeded(&account_id, amount.0);
+                }
+                PromiseResult::Failed => {
+                    self.internal_add_balance(&account_id, amount.0);
+
+                    events::emit::add_withdraw_failed(&account_id, amount.0);
+                }
+            };
+        }
+    }
 }
 
 impl Contract {
@@ -90,4 +147,24 @@ impl Contract {
                     .callback_after_withdraw(account_id.clone(), amount.into()),
             )
     }
+
+    // send several transfers at once, failed ones are returned to internal balances
+    pub fn internal_ft_transfer_batch(&mut self, payouts: Vec<(AccountId, Balance)>) -> Promise {
+        let transfers = payouts
+            .iter()
+            .map(|(account_id, amount)|
+                ext_ft_core::ext(self.ft_account_id.clone())
+                    .with_attached_deposit(NearToken::from_yoctonear(1))
+                    .with_static_gas(GAS_FOR_FT_TRANSFER)
+                    .ft_transfer(account_id.clone(), (*amount).into(), None)
+            )
+            .reduce(|transfers, transfer| transfers.and(transfer))
+            .expect("No payouts");
+
+        transfers.then(
+            ext_self::ext(env::current_account_id())
+                .with_static_gas(GAS_FOR_AFTER_FT_TRANSFER_BATCH)
+                .callback_after_payouts(payouts.into_iter().map(|(account_id, amount)| (account_id, amount.into())).collect()),
+        )
+    }
 }
\ No newline at end of file
@@ -0,0 +1,194 @@
+use crate::*;
+
+pub const DEFAULT_TIMELOCK_PERIOD: u64 = 24 * 60 * 60 * 1_000_000_000; // 1 day
+pub const MIN_TIMELOCK_PERIOD: u64 = 60 * 60 * 1_000_000_000; // 1 hour
+
+// a change of the parameters which affect prices and fees, None removes the override
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Clone)]
+#[borsh(crate = "near_sdk::borsh")]
+#[serde(crate = "near_sdk::serde")]
+pub enum ParameterChange {
+    // global parameters, None keeps the current value
+    Global(EconomicsOverride),
+    GroupEconomics { group: String, economics: Option<EconomicsOverride> },
+    TokenGroup { token_id: TokenId, group: Option<String> },
+    TokenEconomics { token_id: TokenId, economics: Option<EconomicsOverride> },
+    PriceDecay(Option<PriceDecay>),
+}
+
+// the change takes effect at effective_at
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Clone)]
+#[borsh(crate = "near_sdk::borsh")]
+#[serde(crate = "near_sdk::serde")]
+pub struct PendingChange {
+    pub id: u64,
+    pub change: ParameterChange,
+    pub effective_at: U64,
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn get_pending_changes(&self) -> Vec<PendingChange> {
+        let timestamp = env::block_timestamp();
+        self.pending_changes.iter().filter(|change| change.effective_at.0 > timestamp).cloned().collect()
+    }
+
+    pub fn cancel_pending_change(&mut self, id: u64) {
+        self.assert_role(Role::FeeManager);
+        self.internal_apply_pending_changes();
+
+        let len = self.pending_changes.len();
+        self.pending_changes.retain(|change| change.id != id);
+        assert_ne!(len, self.pending_changes.len(), "Pending change not found");
+    }
+
+    // nanoseconds
+    pub fn set_timelock_period(&mut self, timelock_period: U64) {
+        self.assert_owner();
+        assert!(timelock_period.0 >= MIN_TIMELOCK_PERIOD, "Timelock period is too short");
+        self.timelock_period = timelock_period.0;
+    }
+
+    pub fn get_timelock_period(&self) -> U64 {
+        U64(self.timelock_period)
+    }
+}
+
+impl Contract {
+    pub(crate) fn internal_queue_change(&mut self, change: ParameterChange) -> PendingChange {
+        self.internal_apply_pending_changes();
+        self.assert_valid_change(&change);
+
+        let change = PendingChange {
+            id: self.next_change_id,
+            change,
+            effective_at: U64(env::block_timestamp() + self.timelock_period),
+        };
+        self.next_change_id += 1;
+
+        // keep changes ordered by effective_at
+        let index = self.pending_changes.iter().position(|pending| pending.effective_at.0 > change.effective_at.0).unwrap_or(self.pending_changes.len());
+        self.pending_changes.insert(index, change.clone());
+        change
+    }
+
+    // the change must be valid after all pending changes
+    fn assert_valid_change(&self, change: &ParameterChange) {
+        let pending_changes: Vec<&ParameterChange> = self.pending_changes.iter().map(|pending| &pending.change).collect();
+        let mut final_changes = pending_changes.clone();
+        final_changes.push(change);
+
+        match change {
+            ParameterChange::Global(economics) => {
+                economics.assert_valid();
+                let mut final_economics = self.resolve_economics(None, None, &pending_changes);
+                final_economics.apply(economics);
+                final_economics.assert_fees_overflow();
+
+                for group in self.group_economics.keys() {
+                    assert!(!self.resolve_economics(None, Some(&group), &final_changes).fees_overflow(), "Fees overflow in group {}", group);
+                }
+            }
+            ParameterChange::GroupEconomics { group, economics } => {
+                if let Some(economics) = economics {
+                    economics.assert_valid();
+                }
+                self.resolve_economics(None, Some(group), &final_changes).assert_fees_overflow();
+            }
+            ParameterChange::TokenGroup { token_id, .. } => {
+                self.resolve_economics(Some(token_id), None, &final_changes).assert_fees_overflow();
+            }
+            ParameterChange::TokenEconomics { token_id, economics } => {
+                if let Some(economics) = economics {
+                    economics.assert_valid();
+                }
+                self.resolve_economics(Some(token_id), None, &final_changes).assert_fees_overflow();
+            }
+            ParameterChange::PriceDecay(price_decay) => {
+                if let Some(price_decay) = price_decay {
+                    price_decay.assert_valid();
+                }
+            }
+        }
+    }
+
+    // pending changes which reached effective_at but are not applied yet
+    pub(crate) fn get_effective_changes(&self) -> Vec<&ParameterChange> {
+        let timestamp = env::block_timestamp();
+        self.pending_changes
+            .iter()
+            .take_while(|change| change.effective_at.0 <= timestamp)
+            .map(|change| &change.change)
+            .collect()
+    }
+
+    pub(crate) fn get_effective_global_economics(&self) -> TokenEconomics {
+        self.resolve_economics(None, None, &self.get_effective_changes())
+    }
+
+    // price decay with the time it was enabled
+    pub(crate) fn get_effective_price_decay(&self) -> Option<(PriceDecay, Timestamp)> {
+        let timestamp = env::block_timestamp();
+        let mut price_decay = self.price_decay.clone();
+        let mut price_decay_start = self.price_decay_start;
+        for pending in self.pending_changes.iter().take_while(|change| change.effective_at.0 <= timestamp) {
+            if let ParameterChange::PriceDecay(new_price_decay) = &pending.change {
+                if price_decay.is_none() {
+                    price_decay_start = pending.effective_at.0;
+                }
+                price_decay = new_price_decay.clone();
+            }
+        }
+        price_decay.map(|price_decay| (price_decay, price_decay_start))
+    }
+
+    pub(crate) fn internal_apply_pending_changes(&mut self) {
+        let timestamp = env::block_timestamp();
+        let count = self.pending_changes.iter().take_while(|change| change.effective_at.0 <= timestamp).count();
+        if count == 0 {
+            return;
+        }
+
+        let changes: Vec<PendingChange> = self.pending_changes.drain(..count).collect();
+        for PendingChange { change, effective_at, .. } in changes {
+            match change {
+                ParameterChange::Global(economics_override) => {
+                    let mut economics = self.get_global_economics();
+                    economics.apply_if_valid(&economics_override);
+                    self.min_mint_price = economics.min_mint_price.0;
+                    self.price_curve = economics.price_curve;
+                    self.seller_fee = economics.seller_fee;
+                    self.referral_1_fee = economics.referral_1_fee;
+                    self.referral_2_fee = economics.referral_2_fee;
+                }
+                ParameterChange::GroupEconomics { group, economics } => {
+                    if let Some(economics) = economics {
+                        self.group_economics.insert(&group, &economics);
+                    } else {
+                        self.group_economics.remove(&group);
+                    }
+                }
+                ParameterChange::TokenGroup { token_id, group } => {
+                    if let Some(group) = group {
+                        self.token_groups.insert(token_id, group);
+                    } else {
+                        self.token_groups.remove(&token_id);
+                    }
+                }
+                ParameterChange::TokenEconomics { token_id, economics } => {
+                    if let Some(economics) = economics {
+                        self.token_economics.insert(token_id, economics);
+                    } else {
+                        self.token_economics.remove(&token_id);
+                    }
+                }
+                ParameterChange::PriceDecay(price_decay) => {
+                    if self.price_decay.is_none() {
+                        self.price_decay_start = effective_at.0;
+                    }
+                    self.price_decay = price_decay;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,42 @@
+use crate::*;
+
+pub const DEFAULT_MAX_HISTORY_PER_TOKEN: u32 = 20;
+
+// fees distributed on a sale
+pub struct SaleFees {
+    pub seller_fee: Balance,
+    pub referral_1_fee: Balance,
+    pub referral_2_fee: Balance,
+    pub system_fee: Balance,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Clone)]
+#[borsh(crate = "near_sdk::borsh")]
+#[serde(crate = "near_sdk::serde")]
+pub struct SaleRecord {
+    pub generation: TokenGeneration,
+    pub buyer_id: AccountId,
+    // None for the initial mint
+    pub seller_id: Option<AccountId>,
+    pub price: U128,
+    pub seller_payout: U128,
+    pub referral_fees: U128,
+    pub system_fee: U128,
+    pub timestamp: U64,
+}
+
+impl Contract {
+    // keeps only the last max_history_per_token sales of the token
+    pub(crate) fn internal_add_sale_record(&mut self, token_id: &TokenId, sale_record: SaleRecord) {
+        if self.max_history_per_token == 0 {
+            return;
+        }
+
+        let mut history = self.token_history.get(token_id).cloned().unwrap_or_default();
+        history.push(sale_record);
+        if history.len() > self.max_history_per_token as usize {
+            history.drain(..history.len() - self.max_history_per_token as usize);
+        }
+        self.token_history.insert(token_id.clone(), history);
+    }
+}
@@ -1,15 +1,23 @@
-use crate::utils::{assert_fees_overflow, FeeFraction};
+use crate::price::{PriceCurve, PriceDecay};
+use crate::economics::{EconomicsOverride, TokenEconomics};
+use crate::history::{SaleRecord, DEFAULT_MAX_HISTORY_PER_TOKEN};
+use crate::stats::{AccountStats, MarketStats, StatsMetric};
+use crate::pause::{PausableOperation, PauseState};
+use crate::roles::Role;
+use crate::governance::{ParameterChange, PendingChange, DEFAULT_TIMELOCK_PERIOD};
+use crate::migration::{write_state_version, STATE_VERSION};
+use crate::utils::{assert_fees_overflow, fees_overflow, assert_valid_public_key, CollectionItemView, FeeFraction};
 use near_contract_standards::fungible_token::Balance;
 use near_contract_standards::non_fungible_token::core::NonFungibleTokenCore;
 use near_contract_standards::non_fungible_token::metadata::{
     NFTContractMetadata, NonFungibleTokenMetadataProvider, TokenMetadata,
 };
 use near_contract_standards::non_fungible_token::{
-    NonFungibleToken, NonFungibleTokenEnumeration, Token, TokenId,
+    NonFungibleToken, NonFungibleTokenApproval, NonFungibleTokenEnumeration, Token, TokenId,
 };
-use near_sdk::{borsh::{BorshDeserialize, BorshSerialize}, collections::{LazyOption, UnorderedMap, UnorderedSet}, NearToken, env, json_types::U128, Promise, near_bindgen, serde::{Deserialize, Serialize}, AccountId, BorshStorageKey, PanicOnDefault, PromiseOrValue, Timestamp, Gas, ext_contract, log};
+use near_sdk::{borsh::{BorshDeserialize, BorshSerialize}, collections::{LazyOption, UnorderedMap, UnorderedSet}, NearToken, env, json_types::{U128, U64}, Promise, near_bindgen, serde::{Deserialize, Serialize}, AccountId, BorshStorageKey, PanicOnDefault, PromiseOrValue, Timestamp, Gas, ext_contract, log};
 use near_sdk::store::{LookupMap};
-use nft::{nft_without_metadata, generate_token_id};
+use nft::{nft_without_metadata, nft_approvals, generate_token_id, parse_token_id, is_collection_token_id, page_bounds};
 
 mod nft;
 mod utils;
@@ -18,6 +26,14 @@ mod account;
 mod market;
 mod events;
 mod migration;
+mod price;
+mod economics;
+mod history;
+mod stats;
+mod payout;
+mod pause;
+mod roles;
+mod governance;
 
 pub const TIMESTAMP_MAX_INTERVAL: u64 = 5 * 60 * 1_000_000_000;
 
@@ -33,20 +49,67 @@ enum StorageKey {
     UserCollectionItems,
     UserCollectionItemsPerOwner { account_hash: Vec<u8> },
     TokenData,
+    // replaced by UsedMessages, kept to preserve the order of storage keys
+    #[allow(dead_code)]
     LastUserAction,
     Storage,
     StoragePackages,
+    SignerKeys,
+    UsedMessages,
+    PayoutQueue,
+    TokenGroups,
+    GroupEconomics,
+    TokenEconomics,
+    TokenHistory,
+    AccountStats,
+    Approvals,
+    Roles,
+    Leaderboards,
 }
 
 pub type TokenGeneration = u32; // ~ 4.3M resales
 pub type StorageSize = u64;
 pub type StoragePackageIndex = u64;
 
-#[derive(BorshDeserialize, BorshSerialize, Clone)]
+#[derive(BorshSerialize, Clone)]
 #[borsh(crate = "near_sdk::borsh")]
 struct TokenData {
     generation: TokenGeneration,
-    price: Balance
+    price: Balance,
+    // timestamp of the last sale, 0 for tokens sold before it was tracked
+    last_sale: Timestamp,
+    // the token can't be resold before this timestamp
+    available_at: Timestamp
+}
+
+// fields added to TokenData after the first release, missing in old records
+#[derive(BorshDeserialize, Default)]
+#[borsh(crate = "near_sdk::borsh")]
+struct TokenDataExtension {
+    last_sale: Timestamp,
+    available_at: Timestamp
+}
+
+impl BorshDeserialize for TokenData {
+    fn deserialize_reader<R: std::io::Read>(reader: &mut R) -> std::io::Result<Self> {
+        let generation = TokenGeneration::deserialize_reader(reader)?;
+        let price = Balance::deserialize_reader(reader)?;
+
+        let mut extension_bytes = Vec::new();
+        reader.read_to_end(&mut extension_bytes)?;
+        let extension = if extension_bytes.is_empty() {
+            TokenDataExtension::default()
+        } else {
+            TokenDataExtension::try_from_slice(&extension_bytes)?
+        };
+
+        Ok(TokenData {
+            generation,
+            price,
+            last_sale: extension.last_sale,
+            available_at: extension.available_at
+        })
+    }
 }
 
 #[derive(BorshDeserialize, BorshSerialize, PartialEq, Clone, Serialize)]
@@ -65,12 +128,38 @@ struct StoragePackage {
     price: Balance,
 }
 
+#[derive(BorshDeserialize, BorshSerialize, Clone)]
+#[borsh(crate = "near_sdk::borsh")]
+pub struct UsedMessage {
+    // nonce of the message or its timestamp if nonce is missing
+    id: u64,
+    timestamp: Timestamp,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Clone, Deserialize, Serialize)]
+#[borsh(crate = "near_sdk::borsh")]
+#[serde(crate = "near_sdk::serde")]
+pub struct SignerKey {
+    label: String,
+    // the key is not accepted after this timestamp
+    valid_until: Option<U64>,
+}
+
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
 #[borsh(crate = "near_sdk::borsh")]
 pub struct Contract {
     owner_id: AccountId,
-    public_key: String,
+    // must call accept_ownership to become the owner
+    proposed_owner_id: Option<AccountId>,
+    // receives system fees and royalties
+    treasury_id: AccountId,
+    // hex encoded ed25519 public keys allowed to sign purchase messages
+    signer_keys: UnorderedMap<String, SignerKey>,
+    // network tag of signed messages, e.g. "nftinder:mainnet"
+    message_domain: String,
+    // accept messages without contract_id and domain during the migration period
+    accept_legacy_messages: bool,
     min_mint_price: Balance,
     // whitelisted token for deposits
     ft_account_id: AccountId,
@@ -88,22 +177,62 @@ pub struct Contract {
     // generation, price, last_sale
     token_data: LookupMap<TokenId, TokenData>,
 
-    // timestamp of the last purchase to avoid double usage of the signature
-    last_user_action: LookupMap<AccountId, Timestamp>,
+    // ids of recently used purchase messages to avoid double usage of the signature
+    used_messages: LookupMap<AccountId, Vec<UsedMessage>>,
 
     // tokens in user collections
     user_collection_items: UnorderedMap<AccountId, UnorderedSet<CollectionItem>>,
 
+    // how much price grows on new resale
+    price_curve: PriceCurve,
+    // optional decrease of the price of tokens without sales
+    price_decay: Option<PriceDecay>,
+    // when the decay was enabled, tokens without a known sale time are idle since then
+    price_decay_start: Timestamp,
+    // protection of the new owner from resale, nanoseconds
+    sale_cooldown: u64,
+
+    // economics of separate tokens and groups of tokens
+    token_groups: LookupMap<TokenId, String>,
+    group_economics: UnorderedMap<String, EconomicsOverride>,
+    token_economics: LookupMap<TokenId, EconomicsOverride>,
+
     // fees
-    mint_price_increase_fee: FeeFraction,
     seller_fee: FeeFraction,
     referral_1_fee: FeeFraction,
     referral_2_fee: FeeFraction,
+    // NEP-199 royalty of the contract owner on secondary sales of collection items
+    royalty_fee: FeeFraction,
 
     // storage
     storage: LookupMap<AccountId, StorageSize>,
     max_storage_size: StorageSize,
-    storage_packages: UnorderedMap<StoragePackageIndex, StoragePackage>
+    storage_packages: UnorderedMap<StoragePackageIndex, StoragePackage>,
+
+    // accrue seller payouts in internal_balances and send them with flush_payouts
+    batch_payouts: bool,
+    // sellers with payouts waiting for flush_payouts
+    payout_queue: UnorderedSet<AccountId>,
+
+    // last sales of every token
+    token_history: LookupMap<TokenId, Vec<SaleRecord>>,
+    max_history_per_token: u32,
+
+    // trading results of every account
+    account_stats: UnorderedMap<AccountId, AccountStats>,
+    // top accounts of every metric with the metric value
+    leaderboards: LookupMap<StatsMetric, Vec<(AccountId, i128)>>,
+    market_stats: MarketStats,
+
+    // operations stopped by the owner
+    pause_state: PauseState,
+
+    roles: UnorderedMap<AccountId, Vec<Role>>,
+
+    // queued changes of global parameters, ordered by effective_at
+    pending_changes: Vec<PendingChange>,
+    next_change_id: u64,
+    timelock_period: u64
 }
 
 #[derive(Deserialize)]
@@ -115,20 +244,52 @@ pub enum MintNftMsg {
         account_id: AccountId,
         referral_id_1: Option<AccountId>,
         referral_id_2: Option<AccountId>,
-        timestamp: Timestamp
+        timestamp: Timestamp,
+        // unique per account, allows to send several purchases at once
+        #[serde(default)]
+        nonce: Option<u64>,
+        // max price the buyer agreed to pay
+        #[serde(default)]
+        max_price: Option<U128>,
+        // generation of the token after the purchase, 0 for a new token
+        #[serde(default)]
+        generation: Option<TokenGeneration>
+    }
+}
+
+// signed purchase message bound to the contract and the network
+#[derive(Deserialize)]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug, Serialize))]
+#[serde(crate = "near_sdk::serde")]
+pub enum MintNftEnvelope {
+    V2 {
+        contract_id: AccountId,
+        domain: String,
+        message: MintNftMsg
     }
 }
 
+#[derive(Deserialize)]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug, Serialize))]
+#[serde(crate = "near_sdk::serde")]
+#[serde(untagged)]
+pub enum VersionedMintNftMsg {
+    Current(MintNftEnvelope),
+    // bare MintNftMsg, accepted only if accept_legacy_messages is set
+    Legacy(MintNftMsg),
+}
+
 #[near_bindgen]
 impl Contract {
     #[init]
-    // mint_price_increase_fee - how much price grows on new resale
+    // mint_price_increase_fee - how much price grows on new resale, sets PriceCurve::FixedPercentage
     // seller_fee - fee of profit for prev owner
     // referral_fee - fee of profit (new_price - old_price) for referrals
     pub fn new(
         owner_id: AccountId,
         ft_account_id: AccountId,
         public_key: String,
+        message_domain: String,
         min_mint_price: U128,
         mint_price_increase_fee: FeeFraction,
         seller_fee: FeeFraction,
@@ -148,15 +309,26 @@ impl Contract {
         referral_1_fee.assert_valid();
         referral_2_fee.assert_valid();
 
+        assert_valid_public_key(&public_key);
+        let mut signer_keys = UnorderedMap::new(StorageKey::SignerKeys);
+        signer_keys.insert(&public_key, &SignerKey { label: "default".to_string(), valid_until: None });
+
+        write_state_version(STATE_VERSION);
+
         Self {
             owner_id: owner_id.clone(),
+            proposed_owner_id: None,
+            treasury_id: owner_id.clone(),
             ft_account_id: ft_account_id.clone(),
-            public_key,
+            signer_keys,
+            message_domain,
+            accept_legacy_messages: false,
             min_mint_price: min_mint_price.0,
             tokens: nft_without_metadata(
                 StorageKey::NonFungibleToken,
                 owner_id,
                 Some(StorageKey::Enumeration),
+                Some(StorageKey::Approvals),
             ),
             contract_metadata: LazyOption::new(
                 StorageKey::ContractMetadata,
@@ -169,16 +341,42 @@ impl Contract {
             internal_balances: LookupMap::new(StorageKey::InternalBalances),
             is_store_user_tokens: LookupMap::new(StorageKey::StoreUserTokens),
             token_data: LookupMap::new(StorageKey::TokenData),
-            last_user_action: LookupMap::new(StorageKey::LastUserAction),
+            used_messages: LookupMap::new(StorageKey::UsedMessages),
             user_collection_items: UnorderedMap::new(StorageKey::UserCollectionItems),
-            mint_price_increase_fee,
+            price_curve: PriceCurve::FixedPercentage { fee: mint_price_increase_fee },
+            price_decay: None,
+            price_decay_start: 0,
+            sale_cooldown: 0,
+            token_groups: LookupMap::new(StorageKey::TokenGroups),
+            group_economics: UnorderedMap::new(StorageKey::GroupEconomics),
+            token_economics: LookupMap::new(StorageKey::TokenEconomics),
             seller_fee,
             referral_1_fee,
             referral_2_fee,
+            royalty_fee: FeeFraction { numerator: 0, denominator: 1 },
 
             storage: LookupMap::new(StorageKey::Storage),
             max_storage_size,
-            storage_packages: UnorderedMap::new(StorageKey::StoragePackages)
+            storage_packages: UnorderedMap::new(StorageKey::StoragePackages),
+
+            batch_payouts: false,
+            payout_queue: UnorderedSet::new(StorageKey::PayoutQueue),
+
+            token_history: LookupMap::new(StorageKey::TokenHistory),
+            max_history_per_token: DEFAULT_MAX_HISTORY_PER_TOKEN,
+
+            account_stats: UnorderedMap::new(StorageKey::AccountStats),
+
+            leaderboards: LookupMap::new(StorageKey::Leaderboards),
+            market_stats: MarketStats::default(),
+
+            pause_state: PauseState::default(),
+
+            roles: UnorderedMap::new(StorageKey::Roles),
+
+            pending_changes: Vec::new(),
+            next_change_id: 0,
+            timelock_period: DEFAULT_TIMELOCK_PERIOD
         }
     }
 
@@ -189,3 +387,32 @@ impl Contract {
 
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_data_legacy_record() {
+        // (generation, price) records written before last_sale and available_at were added
+        let bytes = near_sdk::borsh::to_vec(&(3 as TokenGeneration, 150 as Balance)).unwrap();
+        let token_data = TokenData::try_from_slice(&bytes).unwrap();
+
+        assert_eq!(token_data.generation, 3);
+        assert_eq!(token_data.price, 150);
+        assert_eq!(token_data.last_sale, 0);
+        assert_eq!(token_data.available_at, 0);
+    }
+
+    #[test]
+    fn token_data_round_trip() {
+        let token_data = TokenData { generation: 7, price: 1_000, last_sale: 1_710_796_871_868_251_000, available_at: 1_710_796_931_868_251_000 };
+        let bytes = near_sdk::borsh::to_vec(&token_data).unwrap();
+        let decoded = TokenData::try_from_slice(&bytes).unwrap();
+
+        assert_eq!(decoded.generation, token_data.generation);
+        assert_eq!(decoded.price, token_data.price);
+        assert_eq!(decoded.last_sale, token_data.last_sale);
+        assert_eq!(decoded.available_at, token_data.available_at);
+    }
+}
@@ -1,15 +1,49 @@
 use crate::*;
 use crate::ft::{GAS_FOR_AFTER_FT_TRANSFER, GAS_FOR_FT_TRANSFER};
+use crate::utils::decode_public_key;
+use crate::history::{SaleFees, SaleRecord};
+use near_contract_standards::non_fungible_token::refund_approved_account_ids;
 
 const GAS_FOR_RESALE: Gas = Gas::from_tgas(GAS_FOR_AFTER_FT_TRANSFER.as_tgas() + GAS_FOR_FT_TRANSFER.as_tgas() + 15u64);
 
 pub const FREE_STORAGE_SIZE: StorageSize = 3;
 
+// max number of not expired purchase messages per account
+pub const MAX_PENDING_MESSAGES: usize = 32;
+
+// reasons to reject a purchase, the deposit is refunded in full and no state is changed
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum PurchaseError {
+    Paused,
+    InvalidSignature,
+    SignatureCheckFailed,
+    WrongMessageFormat,
+    WrongContractId,
+    WrongDomain,
+    LegacyMessageNotAccepted,
+    MintForYourselfOnly,
+    TimestampTooOld,
+    MessageAlreadyUsed,
+    TooManyPendingMessages,
+    InsufficientStorage,
+    NotEnoughGas,
+    InsufficientDeposit,
+    PriceAboveMaximum,
+    GenerationMismatch,
+    SameOwner,
+    TokenProtected,
+    FeesOverflow,
+}
+
 impl Contract {
-    pub(crate) fn get_new_token_data(&self) -> TokenData {
+    pub(crate) fn get_new_token_data(&self, token_id: &TokenId) -> TokenData {
         TokenData {
             generation: 0u32,
-            price: self.min_mint_price
+            price: self.internal_get_token_economics(token_id).min_mint_price.0,
+            last_sale: 0,
+            available_at: 0
         }
     }
 
@@ -17,18 +51,55 @@ impl Contract {
         self
             .token_data
             .get(token_id)
-            .unwrap_or(&self.get_new_token_data())
+            .unwrap_or(&self.get_new_token_data(token_id))
             .clone()
     }
 
-    pub(crate) fn get_token_price(&self, token_id: &TokenId) -> Balance{
-        self.get_token_data(token_id).price
+    // token data with the price decreased by the price decay
+    pub(crate) fn get_current_token_data(&self, token_id: &TokenId) -> TokenData {
+        let mut token_data = self.get_token_data(token_id);
+        if let Some((price_decay, price_decay_start)) = self.get_effective_price_decay() {
+            let min_price = self.internal_get_token_economics(token_id).min_mint_price.0;
+            // last_sale is 0 for tokens sold before it was tracked
+            let idle_since = std::cmp::max(token_data.last_sale, price_decay_start);
+            token_data.price = price_decay.decayed_price(token_data.price, min_price, idle_since, env::block_timestamp());
+        }
+        token_data
+    }
+
+    // data of the token sold in the current block
+    pub(crate) fn get_sold_token_data(&self, token_id: &TokenId, generation: TokenGeneration, price: Balance) -> TokenData {
+        let sale_cooldown = self.internal_get_token_economics(token_id).sale_cooldown.0;
+        TokenData {
+            generation,
+            price,
+            last_sale: env::block_timestamp(),
+            available_at: env::block_timestamp() + sale_cooldown
+        }
+    }
+
+    // price of the next resale
+    pub(crate) fn get_next_price(&self, token_id: &TokenId, token_data: &TokenData) -> Balance {
+        self.internal_get_token_economics(token_id).price_curve.next_price(token_data.price, token_data.generation + 1)
     }
 
     pub(crate) fn get_token_generation(&self, token_id: &TokenId) -> TokenGeneration {
         self.get_token_data(token_id).generation
     }
 
+    // used messages which are not expired yet, expired messages are rejected by timestamp anyway
+    pub(crate) fn get_pending_used_messages(&self, account_id: &AccountId) -> Vec<UsedMessage> {
+        let block_timestamp = env::block_timestamp();
+        self.used_messages
+            .get(account_id)
+            .map(|used_messages| used_messages
+                .iter()
+                .filter(|used_message| used_message.timestamp + TIMESTAMP_MAX_INTERVAL >= block_timestamp)
+                .cloned()
+                .collect())
+            .unwrap_or_default()
+    }
+
     pub(crate) fn get_user_collection(&self, account_id: &AccountId) -> Option<UnorderedSet<CollectionItem>> {
         self.user_collection_items.get(account_id)
     }
@@ -48,6 +119,12 @@ impl Contract {
 
 
         // add to user collection
+        self.internal_add_collection_item(account_id, CollectionItem {token_id: token_id.clone(), generation});
+
+        self.market_stats.collection_items += 1;
+    }
+
+    fn internal_add_collection_item(&mut self, account_id: &AccountId, collection_item: CollectionItem) {
         let mut user_collection = if let Some(user_collection) =  self.user_collection_items.get(account_id) {
             user_collection
         }
@@ -59,80 +136,149 @@ impl Contract {
             )
         };
 
-        user_collection.insert(&CollectionItem {token_id: token_id.clone(), generation});
+        user_collection.insert(&collection_item);
         self.user_collection_items.insert(account_id, &user_collection);
     }
 
+    // move collection item of a transferred "<generation>:<ipfs_hash>" token
+    pub(crate) fn internal_move_collection_item(&mut self, from_id: &AccountId, to_id: &AccountId, full_token_id: &TokenId) {
+        let (generation, token_id) = parse_token_id(full_token_id.clone());
+        let collection_item = CollectionItem {token_id, generation};
+
+        let mut user_collection = self.user_collection_items.get(from_id).expect("Not found");
+        assert!(user_collection.remove(&collection_item), "Not found");
+        self.user_collection_items.insert(from_id, &user_collection);
+
+        self.internal_add_collection_item(to_id, collection_item);
+    }
+
     /* message - a stringified JSON Object
-    {
-       "token_id": "<ipfs_hash>",
-       "account_id": "buyer_name.near",
-       "referral_id_1": "ref.near",
-       "referral_id_2": null,
-       timestamp: Timestamp
-    },
-    signature - message signed with self.public_key
+    {"V2": {
+       "contract_id": "<current_account_id>",
+       "domain": "<self.message_domain>",
+       "message": {"SimpleMint": {
+          "token_id": "<ipfs_hash>",
+          "account_id": "buyer_name.near",
+          "referral_id_1": "ref.near",
+          "referral_id_2": null,
+          "timestamp": Timestamp,
+          "nonce": u64 (optional, unique per account, timestamp is used if missing),
+          "max_price": U128 (optional, max price the buyer agreed to pay),
+          "generation": TokenGeneration (optional, generation of the token after the purchase, 0 for a new token)
+       }}
+    }}
+    A bare {"SimpleMint": {..}} message is accepted while self.accept_legacy_messages is set.
+    signature - message signed with one of the active self.signer_keys
 
     This function doesn't check if buyer has enough storage to keep the token. We expect server to make this check before to verify the transaction.
+
+    Returns the amount of the deposit used for the purchase, the rest is refunded by ft_on_transfer.
+    On PurchaseError nothing is stored and the whole deposit is refunded.
     */
 
 
-    pub(crate) fn nft_mint(&mut self, message: String, signature: String, receiver_id: AccountId, deposit: Balance) -> PromiseOrValue<bool> {
-        let mut pk = [0u8; 32];
-        let v = hex::decode(&self.public_key).expect("Failed to decode public key");
-        assert_eq!(pk.len(), v.len(), "Invalid key length");
-        pk.copy_from_slice(&v);
+    pub(crate) fn nft_mint(&mut self, message: String, signature: String, receiver_id: AccountId, deposit: Balance) -> Result<Balance, PurchaseError> {
+        if self.pause_state.purchases {
+            return Err(PurchaseError::Paused);
+        }
 
         let mut sig = [0u8; 64];
-        let v = hex::decode(&signature).expect("Failed to decode signature");
-        assert_eq!(sig.len(), v.len(), "Invalid signature length");
+        let v = hex::decode(&signature).map_err(|_| PurchaseError::InvalidSignature)?;
+        if sig.len() != v.len() {
+            return Err(PurchaseError::InvalidSignature);
+        }
         sig.copy_from_slice(&v);
 
-        assert!(verification(&pk, &message, &sig), "Signature check failed");
+        // any active signer key is accepted
+        let block_timestamp = env::block_timestamp();
+        let is_signed = self.signer_keys.iter().any(|(public_key, signer_key)|
+            signer_key.is_active(block_timestamp) && verification(&decode_public_key(&public_key), &message, &sig)
+        );
+        if !is_signed {
+            return Err(PurchaseError::SignatureCheckFailed);
+        }
 
-        let parsed_message = serde_json::from_str::<MintNftMsg>(&message).expect("Wrong message format");
+        let parsed_message = match serde_json::from_str::<VersionedMintNftMsg>(&message).map_err(|_| PurchaseError::WrongMessageFormat)? {
+            VersionedMintNftMsg::Current(MintNftEnvelope::V2 { contract_id, domain, message }) => {
+                if contract_id != env::current_account_id() {
+                    return Err(PurchaseError::WrongContractId);
+                }
+                if domain != self.message_domain {
+                    return Err(PurchaseError::WrongDomain);
+                }
+                message
+            }
+            VersionedMintNftMsg::Legacy(message) => {
+                if !self.accept_legacy_messages {
+                    return Err(PurchaseError::LegacyMessageNotAccepted);
+                }
+                message
+            }
+        };
 
         match parsed_message {
             MintNftMsg::SimpleMint {
-                token_id, account_id, referral_id_1, referral_id_2, timestamp
+                token_id, account_id, referral_id_1, referral_id_2, timestamp, nonce, max_price, generation
             } => {
-                assert_eq!(receiver_id, account_id, "Mint for yourself only");
+                if receiver_id != account_id {
+                    return Err(PurchaseError::MintForYourselfOnly);
+                }
 
-                assert!(
-                    timestamp + TIMESTAMP_MAX_INTERVAL >= env::block_timestamp(),
-                    "Timestamp is too old"
-                );
+                if timestamp + TIMESTAMP_MAX_INTERVAL < env::block_timestamp() {
+                    return Err(PurchaseError::TimestampTooOld);
+                }
 
-                if let Some(user_last_action) = self.last_user_action.get(&account_id) {
-                    assert!(
-                        timestamp > *user_last_action,
-                        "Timestamp is smaller then last user's action"
-                    );
+                let message_id = nonce.unwrap_or(timestamp);
+                let mut used_messages = self.get_pending_used_messages(&account_id);
+                if used_messages.iter().any(|used_message| used_message.id == message_id) {
+                    return Err(PurchaseError::MessageAlreadyUsed);
                 }
+                if used_messages.len() >= MAX_PENDING_MESSAGES {
+                    return Err(PurchaseError::TooManyPendingMessages);
+                }
+                used_messages.push(UsedMessage { id: message_id, timestamp });
 
                 let buyer_storage_size = self.internal_get_user_storage(&account_id);
                 let buyer_storage_used = self.internal_total_supply_by_user(&account_id);
-                assert!(buyer_storage_size > buyer_storage_used, "Insufficient Storage");
+                if buyer_storage_size <= buyer_storage_used {
+                    return Err(PurchaseError::InsufficientStorage);
+                }
 
-                // save buyer's action
-                self.last_user_action.insert(account_id, env::block_timestamp());
+                // a global fee change may overflow the fees together with a group or token override
+                if self.internal_get_token_economics(&token_id).fees_overflow() {
+                    return Err(PurchaseError::FeesOverflow);
+                }
 
                 if let Some(token) = self.tokens.nft_token(token_id.clone()) {
                     // token already exists
-                    assert!(remaining_gas() >= GAS_FOR_RESALE, "Attach more gas");
+                    if !self.batch_payouts && remaining_gas() < GAS_FOR_RESALE {
+                        return Err(PurchaseError::NotEnoughGas);
+                    }
 
-                    let token_data: TokenData = self.get_token_data(&token_id);
+                    let token_data: TokenData = self.get_current_token_data(&token_id);
+                    // price paid by the seller, before the price decay
+                    let purchase_price: Balance = self.get_token_data(&token_id).price;
                     let old_price: Balance = token_data.price;
                     let old_generation: TokenGeneration = token_data.generation;
 
-                    let price_increase = self.mint_price_increase_fee.multiply(old_price);
-                    let new_price = old_price + price_increase;
+                    if env::block_timestamp() < token_data.available_at {
+                        return Err(PurchaseError::TokenProtected);
+                    }
+
+                    let new_price = self.get_next_price(&token_id, &token_data);
+                    let price_increase = new_price - old_price;
 
-                    assert_deposit(deposit, new_price);
+                    check_expected_sale(new_price, old_generation + 1, max_price, generation)?;
+                    check_deposit(deposit, new_price)?;
 
                     // distribute seller reward
                     let seller_id: AccountId = token.owner_id.clone();
-                    assert_ne!(seller_id, receiver_id, "Current and next owner must differ");
+                    if seller_id == receiver_id {
+                        return Err(PurchaseError::SameOwner);
+                    }
+
+                    // save used message
+                    self.used_messages.insert(account_id, used_messages);
 
                     let seller_storage_size = self.internal_get_user_storage(&seller_id);
 
@@ -144,11 +290,12 @@ impl Contract {
 
                     // update token data
                     self.token_data.insert(token_id.clone(),
-                                           TokenData { generation: old_generation + 1, price: new_price});
+                                           self.get_sold_token_data(&token_id, old_generation + 1, new_price));
 
                     // fees on nft price increase
-                    let seller_fee = self.manage_fees(false, &token_id, &receiver_id, price_increase, referral_id_1, referral_id_2);
+                    let fees = self.manage_fees(false, &token_id, &receiver_id, price_increase, referral_id_1, referral_id_2);
 
+                    // emits NEP-171 nft_transfer
                     self.tokens.internal_transfer(
                         &seller_id,
                         &receiver_id,
@@ -157,46 +304,88 @@ impl Contract {
                         None,
                     );
 
-                    let seller_payout = old_price + seller_fee;
+                    let seller_payout = old_price + fees.seller_fee;
                     events::emit::add_seller_payout(&receiver_id, &token_id, seller_payout);
 
-                    // ft transfer to seller here instead
-                    PromiseOrValue::Promise(self.internal_ft_transfer(&seller_id, seller_payout))
+                    self.internal_add_purchase_stats(&receiver_id, new_price, price_increase - fees.seller_fee);
+                    self.internal_add_sale_stats(&seller_id, new_price, seller_payout, purchase_price);
+                    self.market_stats.add_sale(new_price, old_generation + 1);
+
+                    self.internal_add_sale_record(&token_id, SaleRecord {
+                        generation: old_generation + 1,
+                        buyer_id: receiver_id.clone(),
+                        seller_id: Some(seller_id.clone()),
+                        price: U128(new_price),
+                        seller_payout: U128(seller_payout),
+                        referral_fees: U128(fees.referral_1_fee + fees.referral_2_fee),
+                        system_fee: U128(fees.system_fee),
+                        timestamp: U64(env::block_timestamp()),
+                    });
+
+                    if self.batch_payouts {
+                        // sent later by flush_payouts
+                        self.internal_add_balance(&seller_id, seller_payout);
+                        self.payout_queue.insert(&seller_id);
+                    } else {
+                        // ft transfer to seller here instead
+                        self.internal_ft_transfer(&seller_id, seller_payout);
+                    }
 
+                    Ok(new_price)
                 } else {
                     // create new token
-                    let min_price = self.min_mint_price;
+                    let min_price = self.internal_get_token_economics(&token_id).min_mint_price.0;
 
-                    assert_deposit(deposit, min_price);
+                    check_expected_sale(min_price, 0, max_price, generation)?;
+                    check_deposit(deposit, min_price)?;
 
-                    // fees on initial payment
-                    self.manage_fees(true, &token_id, &receiver_id, min_price, referral_id_1, referral_id_2);
+                    // save used message
+                    self.used_messages.insert(account_id, used_messages);
 
-                    self.token_data.insert(token_id.clone(), TokenData { generation: 0, price: min_price });
+                    // fees on initial payment
+                    let fees = self.manage_fees(true, &token_id, &receiver_id, min_price, referral_id_1, referral_id_2);
+
+                    self.token_data.insert(token_id.clone(), self.get_sold_token_data(&token_id, 0, min_price));
+                    self.internal_add_purchase_stats(&receiver_id, min_price, min_price);
+                    self.market_stats.add_sale(min_price, 0);
+                    self.market_stats.distinct_tokens += 1;
+                    self.internal_add_sale_record(&token_id, SaleRecord {
+                        generation: 0,
+                        buyer_id: receiver_id.clone(),
+                        seller_id: None,
+                        price: U128(min_price),
+                        seller_payout: U128(0),
+                        referral_fees: U128(fees.referral_1_fee + fees.referral_2_fee),
+                        system_fee: U128(fees.system_fee),
+                        timestamp: U64(env::block_timestamp()),
+                    });
                     self.internal_mint_without_storage(token_id, receiver_id);
 
-                    PromiseOrValue::Value(true)
+                    Ok(min_price)
                 }
             }
         }
     }
 
-    // returns seller fee
-    pub(crate) fn manage_fees (&mut self, initial_sale: bool, token_id: &TokenId, account_id: &AccountId, price_increase: Balance, referral_id_1: Option<AccountId>, referral_id_2: Option<AccountId>) -> Balance {
-        let seller_fee: Balance = if !initial_sale { self.seller_fee.multiply(price_increase) } else { 0 };
+    // returns distributed fees
+    pub(crate) fn manage_fees (&mut self, initial_sale: bool, token_id: &TokenId, account_id: &AccountId, price_increase: Balance, referral_id_1: Option<AccountId>, referral_id_2: Option<AccountId>) -> SaleFees {
+        let economics = self.internal_get_token_economics(token_id);
+        let seller_fee: Balance = if !initial_sale { economics.seller_fee.multiply(price_increase) } else { 0 };
 
         // distribute affiliate reward
         let mut referral_1_fee: Balance = 0;
         let mut referral_2_fee: Balance = 0;
         if let Some(referral_1) = referral_id_1 {
-            referral_1_fee = self.referral_1_fee.multiply(price_increase);
+            referral_1_fee = economics.referral_1_fee.multiply(price_increase);
             events::emit::add_referral_fee(&referral_1, account_id, token_id, referral_1_fee);
             self.internal_add_balance(&referral_1, referral_1_fee);
+            self.internal_add_referral_stats(&referral_1, referral_1_fee);
         }
         if let Some(referral_2) = referral_id_2 {
-            referral_2_fee = self.referral_2_fee.multiply(price_increase);
+            referral_2_fee = economics.referral_2_fee.multiply(price_increase);
             events::emit::add_referral_fee(&referral_2, account_id, token_id, referral_2_fee);
             self.internal_add_balance(&referral_2, referral_2_fee);
+            self.internal_add_referral_stats(&referral_2, referral_2_fee);
         }
 
         // distribute system reward
@@ -216,18 +405,26 @@ impl Contract {
         }
 
         if let Some(system_fee) = system_fee {
-            events::emit::add_system_fee(&self.owner_id, token_id, system_fee);
-            self.internal_add_balance(&self.owner_id.clone(), system_fee);
+            events::emit::add_system_fee(&self.treasury_id, token_id, system_fee);
+            self.internal_add_balance(&self.treasury_id.clone(), system_fee);
         }
 
-        seller_fee
+        self.market_stats.add_fees(system_fee.unwrap_or(0), referral_1_fee + referral_2_fee);
+
+        SaleFees {
+            seller_fee,
+            referral_1_fee,
+            referral_2_fee,
+            system_fee: system_fee.unwrap_or(0),
+        }
     }
 
     pub(crate) fn internal_get_user_storage(&self, account_id: &AccountId) -> StorageSize {
         self.storage.get(account_id).unwrap_or(&FREE_STORAGE_SIZE).clone()
     }
 
-    pub(crate) fn buy_storage(&mut self, receiver_id: AccountId, deposit: Balance, index: StoragePackageIndex) {
+    // returns the amount of the deposit used for the package
+    pub(crate) fn buy_storage(&mut self, receiver_id: AccountId, deposit: Balance, index: StoragePackageIndex) -> Balance {
         let package = self.storage_packages.get(&index).expect("Missing Storage Package");
         assert!(deposit >= package.price , "Illegal Deposit");
 
@@ -236,6 +433,8 @@ impl Contract {
         assert!(new_storage <= self.max_storage_size, "Illegal Storage To Buy");
 
         self.storage.insert(receiver_id, new_storage);
+
+        package.price
     }
 
     pub(crate) fn internal_remove_user_collection_item(&mut self, account_id: AccountId, generation: TokenGeneration, token_id: TokenId, verify_data: bool) {
@@ -249,6 +448,8 @@ impl Contract {
 
             user_collection.remove(&item_to_remove);
             self.user_collection_items.insert(&account_id, &user_collection);
+
+            self.market_stats.collection_items -= 1;
         }
         else {
             if verify_data {
@@ -260,7 +461,21 @@ impl Contract {
         if verify_data {
             assert!(self.tokens.owner_by_id.contains_key(&full_token_id), "Token not found (owner_by_id)");
         }
-        self.tokens.owner_by_id.remove(&full_token_id);
+        if self.tokens.owner_by_id.remove(&full_token_id).is_some() {
+            let predecessor_id = env::predecessor_account_id();
+            let authorized_id = if predecessor_id != account_id { Some(&predecessor_id) } else { None };
+            events::emit::add_nep171_burn(&account_id, &full_token_id, authorized_id);
+        }
+
+        // remove approvals and refund their storage
+        if let Some(approvals_by_id) = &mut self.tokens.approvals_by_id {
+            if let Some(approved_account_ids) = approvals_by_id.remove(&full_token_id) {
+                refund_approved_account_ids(account_id.clone(), &approved_account_ids);
+            }
+        }
+        if let Some(next_approval_id_by_id) = &mut self.tokens.next_approval_id_by_id {
+            next_approval_id_by_id.remove(&full_token_id);
+        }
 
         if let Some(tokens_per_owner) = &mut self.tokens.tokens_per_owner {
             let mut token_ids = tokens_per_owner.get(&account_id).expect("Not found");
@@ -273,8 +488,27 @@ impl Contract {
     }
 }
 
-fn assert_deposit(deposit: Balance, price: Balance) {
-    assert!(deposit >= price, "Illegal deposit, add extra {} yNEAR", price - deposit);
+fn check_deposit(deposit: Balance, price: Balance) -> Result<(), PurchaseError> {
+    if deposit < price {
+        log!("Illegal deposit, add extra {} yNEAR", price - deposit);
+        return Err(PurchaseError::InsufficientDeposit);
+    }
+    Ok(())
+}
+
+// verify the sale the buyer has signed for is still actual
+fn check_expected_sale(price: Balance, generation: TokenGeneration, max_price: Option<U128>, expected_generation: Option<TokenGeneration>) -> Result<(), PurchaseError> {
+    if let Some(max_price) = max_price {
+        if price > max_price.0 {
+            return Err(PurchaseError::PriceAboveMaximum);
+        }
+    }
+    if let Some(expected_generation) = expected_generation {
+        if generation != expected_generation {
+            return Err(PurchaseError::GenerationMismatch);
+        }
+    }
+    Ok(())
 }
 
 fn verification(pk_string: &[u8; 32], message: &str, sig_string: &[u8; 64]) -> bool {
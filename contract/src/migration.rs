@@ -1,70 +1,204 @@
 use crate::*;
+use near_sdk::require;
+
+// version of the state layout written by this code
+pub const STATE_VERSION: u32 = 2;
+
+// stored outside of the contract struct to be readable before the layout is known
+const STATE_VERSION_KEY: &[u8] = b"STATE_VERSION";
+
+const GAS_FOR_UPGRADE: Gas = Gas::from_tgas(10);
+
+// arguments of the migration steps, a step panics if its argument is missing
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Default)]
+#[borsh(crate = "near_sdk::borsh")]
+#[serde(crate = "near_sdk::serde")]
+pub struct MigrateArgs {
+    // v1 -> v2
+    pub message_domain: Option<String>,
+    // v1 -> v2, number of items in all user collections, counted off-chain to keep the migration gas bounded
+    pub collection_items: Option<U64>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct VersionView {
+    pub code_version: String,
+    pub state_version: u32,
+}
 
 #[near_bindgen]
 impl Contract {
+    // borsh input: the new contract code and the arguments of migrate, deploys the code and calls migrate
+    pub fn upgrade(&self, #[serializer(borsh)] code: Vec<u8>, #[serializer(borsh)] migrate_args: Option<MigrateArgs>) -> Promise {
+        self.assert_owner();
+        let remaining_gas = env::prepaid_gas().as_gas() - env::used_gas().as_gas();
+        require!(remaining_gas > GAS_FOR_UPGRADE.as_gas(), "Attach more gas");
+        let migrate_args = near_sdk::serde_json::to_vec(&near_sdk::serde_json::json!({ "args": migrate_args })).unwrap();
+
+        Promise::new(env::current_account_id())
+            .deploy_contract(code)
+            .function_call(
+                "migrate".to_string(),
+                migrate_args,
+                NearToken::from_yoctonear(0),
+                Gas::from_gas(remaining_gas - GAS_FOR_UPGRADE.as_gas()),
+            )
+    }
+
+    // applies the migration steps from the stored state version to STATE_VERSION in order
     #[init(ignore_state)]
-    #[allow(dead_code)]
     #[private]
-    pub fn migrate_1(max_storage_size: StorageSize) -> Self {
-        #[derive(BorshDeserialize)]
-        #[borsh(crate = "near_sdk::borsh")]
-        struct OldContract {
-            owner_id: AccountId,
-            public_key: String,
-            min_mint_price: Balance,
-            // whitelisted token for deposits
-            ft_account_id: AccountId,
-
-            tokens: NonFungibleToken,
-            contract_metadata: LazyOption<NFTContractMetadata>,
-            token_metadata: LazyOption<TokenMetadata>,
-
-            // referral rewards
-            internal_balances: LookupMap<AccountId, Balance>,
-
-            // shall we store user tokens
-            is_store_user_tokens: LookupMap<AccountId, bool>,
-
-            // generation, price, last_sale
-            token_data: LookupMap<TokenId, TokenData>,
-
-            // timestamp of the last purchase to avoid double usage of the signature
-            last_user_action: LookupMap<AccountId, Timestamp>,
-
-            // tokens in user collections
-            //user_collection_items_1: LookupMap<AccountId, Vec<CollectionItem>>,
-            user_collection_items: UnorderedMap<AccountId, UnorderedSet<CollectionItem>>,
-
-            // fees
-            mint_price_increase_fee: FeeFraction,
-            seller_fee: FeeFraction,
-            referral_1_fee: FeeFraction,
-            referral_2_fee: FeeFraction,
+    pub fn migrate(args: Option<MigrateArgs>) -> Self {
+        let args = args.unwrap_or_default();
+
+        let mut state_version = read_state_version();
+        assert!(state_version <= STATE_VERSION, "State version {} is newer than the code", state_version);
+
+        while state_version < STATE_VERSION {
+            match state_version {
+                1 => migrate_1_to_2(&args),
+                _ => env::panic_str(&format!("No migration from state version {}", state_version)),
+            }
+            state_version += 1;
         }
 
-        let old_contract: OldContract = env::state_read().expect("Old state doesn't exist");
-
-        Self {
-            owner_id: old_contract.owner_id,
-            public_key: old_contract.public_key,
-            min_mint_price: old_contract.min_mint_price,
-            ft_account_id: old_contract.ft_account_id,
-            tokens: old_contract.tokens,
-            contract_metadata: old_contract.contract_metadata,
-            token_metadata: old_contract.token_metadata,
-            internal_balances: old_contract.internal_balances,
-            is_store_user_tokens: old_contract.is_store_user_tokens,
-            token_data: old_contract.token_data,
-            last_user_action: old_contract.last_user_action,
-            user_collection_items: old_contract.user_collection_items,
-            mint_price_increase_fee: old_contract.mint_price_increase_fee,
-            seller_fee: old_contract.seller_fee,
-            referral_1_fee: old_contract.referral_1_fee,
-            referral_2_fee: old_contract.referral_2_fee,
-
-            storage: LookupMap::new(StorageKey::Storage),
-            max_storage_size,
-            storage_packages: UnorderedMap::new(StorageKey::StoragePackages)
+        write_state_version(STATE_VERSION);
+        env::state_read().expect("State doesn't exist")
+    }
+
+    pub fn get_version(&self) -> VersionView {
+        VersionView {
+            code_version: env!("CARGO_PKG_VERSION").to_string(),
+            state_version: read_state_version(),
         }
     }
-}
\ No newline at end of file
+}
+
+// state without a stored version was written before the versioning
+fn read_state_version() -> u32 {
+    env::storage_read(STATE_VERSION_KEY)
+        .map(|value| u32::try_from_slice(&value).expect("Invalid state version"))
+        .unwrap_or(1)
+}
+
+pub(crate) fn write_state_version(state_version: u32) {
+    env::storage_write(STATE_VERSION_KEY, &near_sdk::borsh::to_vec(&state_version).unwrap());
+}
+
+// signer registry, message domain, token economics, history, stats, approvals, governance
+fn migrate_1_to_2(args: &MigrateArgs) {
+    #[derive(BorshDeserialize)]
+    #[borsh(crate = "near_sdk::borsh")]
+    struct OldContract {
+        owner_id: AccountId,
+        public_key: String,
+        min_mint_price: Balance,
+        // whitelisted token for deposits
+        ft_account_id: AccountId,
+
+        tokens: NonFungibleToken,
+        contract_metadata: LazyOption<NFTContractMetadata>,
+        token_metadata: LazyOption<TokenMetadata>,
+
+        // referral rewards + refunds for fallen withdrawals
+        internal_balances: LookupMap<AccountId, Balance>,
+
+        // shall we store user tokens in user_collection
+        is_store_user_tokens: LookupMap<AccountId, bool>,
+
+        // generation, price, last_sale
+        token_data: LookupMap<TokenId, TokenData>,
+
+        // timestamp of the last purchase to avoid double usage of the signature, replaced by used_messages
+        #[allow(dead_code)]
+        last_user_action: LookupMap<AccountId, Timestamp>,
+
+        // tokens in user collections
+        user_collection_items: UnorderedMap<AccountId, UnorderedSet<CollectionItem>>,
+
+        // fees
+        mint_price_increase_fee: FeeFraction,
+        seller_fee: FeeFraction,
+        referral_1_fee: FeeFraction,
+        referral_2_fee: FeeFraction,
+
+        // storage
+        storage: LookupMap<AccountId, StorageSize>,
+        max_storage_size: StorageSize,
+        storage_packages: UnorderedMap<StoragePackageIndex, StoragePackage>
+    }
+
+    let old_contract: OldContract = env::state_read().expect("Old state doesn't exist");
+
+    // previous sales are unknown, only count existing tokens
+    let collection_items: u64 = args.collection_items.expect("collection_items is required to migrate from version 1").0;
+    let market_stats = MarketStats {
+        distinct_tokens: old_contract.tokens.owner_by_id.len().saturating_sub(collection_items),
+        collection_items,
+        ..MarketStats::default()
+    };
+
+    // enable approvals for collection items
+    let mut tokens = old_contract.tokens;
+    (tokens.approvals_by_id, tokens.next_approval_id_by_id) = nft_approvals(Some(StorageKey::Approvals));
+
+    // the single public key becomes the first key of the signer registry
+    let mut signer_keys = UnorderedMap::new(StorageKey::SignerKeys);
+    signer_keys.insert(&old_contract.public_key, &SignerKey { label: "default".to_string(), valid_until: None });
+
+    let contract = Contract {
+        owner_id: old_contract.owner_id.clone(),
+        proposed_owner_id: None,
+        treasury_id: old_contract.owner_id,
+        signer_keys,
+        message_domain: args.message_domain.clone().expect("message_domain is required to migrate from version 1"),
+        accept_legacy_messages: true,
+        min_mint_price: old_contract.min_mint_price,
+        ft_account_id: old_contract.ft_account_id,
+        tokens,
+        contract_metadata: old_contract.contract_metadata,
+        token_metadata: old_contract.token_metadata,
+        internal_balances: old_contract.internal_balances,
+        is_store_user_tokens: old_contract.is_store_user_tokens,
+        token_data: old_contract.token_data,
+        used_messages: LookupMap::new(StorageKey::UsedMessages),
+        user_collection_items: old_contract.user_collection_items,
+        price_curve: PriceCurve::FixedPercentage { fee: old_contract.mint_price_increase_fee },
+        price_decay: None,
+        price_decay_start: 0,
+        sale_cooldown: 0,
+        token_groups: LookupMap::new(StorageKey::TokenGroups),
+        group_economics: UnorderedMap::new(StorageKey::GroupEconomics),
+        token_economics: LookupMap::new(StorageKey::TokenEconomics),
+        seller_fee: old_contract.seller_fee,
+        referral_1_fee: old_contract.referral_1_fee,
+        referral_2_fee: old_contract.referral_2_fee,
+        royalty_fee: FeeFraction { numerator: 0, denominator: 1 },
+
+        storage: old_contract.storage,
+        max_storage_size: old_contract.max_storage_size,
+        storage_packages: old_contract.storage_packages,
+
+        batch_payouts: false,
+        payout_queue: UnorderedSet::new(StorageKey::PayoutQueue),
+
+        token_history: LookupMap::new(StorageKey::TokenHistory),
+        max_history_per_token: DEFAULT_MAX_HISTORY_PER_TOKEN,
+
+        account_stats: UnorderedMap::new(StorageKey::AccountStats),
+
+        leaderboards: LookupMap::new(StorageKey::Leaderboards),
+        market_stats,
+
+        pause_state: PauseState::default(),
+
+        roles: UnorderedMap::new(StorageKey::Roles),
+
+        pending_changes: Vec::new(),
+        next_change_id: 0,
+        timelock_period: DEFAULT_TIMELOCK_PERIOD
+    };
+
+    env::state_write(&contract);
+}
@@ -1,6 +1,17 @@
 use crate::*;
+use crate::ft::ext_self;
+use near_contract_standards::non_fungible_token::core::NonFungibleTokenResolver;
 use near_sdk::collections::{LookupMap, TreeMap, UnorderedSet};
-use near_sdk::{require, IntoStorageKey};
+use near_sdk::{assert_one_yocto, require, IntoStorageKey};
+use std::collections::HashMap;
+
+const GAS_FOR_RESOLVE_TRANSFER: Gas = Gas::from_tgas(10);
+const GAS_FOR_NFT_TRANSFER_CALL: Gas = Gas::from_tgas(25 + GAS_FOR_RESOLVE_TRANSFER.as_tgas());
+
+#[ext_contract(ext_nft_receiver)]
+trait ExtNftReceiver {
+    fn nft_on_transfer(&mut self, sender_id: AccountId, previous_owner_id: AccountId, token_id: TokenId, msg: String) -> PromiseOrValue<bool>;
+}
 
 impl Contract {
     pub fn get_token_metadata(&self, token_id: &TokenId) -> TokenMetadata {
@@ -58,6 +69,8 @@ impl Contract {
             tokens_per_owner.insert(&owner_id, &token_ids);
         }
 
+        events::emit::add_nep171_mint(&owner_id, &token_id);
+
         Token {
             token_id,
             owner_id,
@@ -66,6 +79,27 @@ impl Contract {
         }
     }
 
+    // tradable tokens are sold with nft_mint only
+    pub(crate) fn internal_transfer_collection_item(
+        &mut self,
+        sender_id: &AccountId,
+        receiver_id: &AccountId,
+        token_id: &TokenId,
+        approval_id: Option<u64>,
+        memo: Option<String>,
+    ) -> (AccountId, Option<HashMap<AccountId, u64>>) {
+        require!(is_collection_token_id(token_id), "Not allowed");
+        require!(
+            self.internal_get_user_storage(receiver_id) > self.internal_total_supply_by_user(receiver_id),
+            "Insufficient Storage"
+        );
+
+        let (previous_owner_id, approved_account_ids) = self.tokens.internal_transfer(sender_id, receiver_id, token_id, approval_id, memo);
+        self.internal_move_collection_item(&previous_owner_id, receiver_id, token_id);
+
+        (previous_owner_id, approved_account_ids)
+    }
+
     pub(crate) fn internal_total_supply_by_user(&self, account_id: &AccountId) -> StorageSize {
         if let Some(tokens_per_owner) = self.tokens.tokens_per_owner.as_ref() {
             tokens_per_owner
@@ -78,33 +112,53 @@ impl Contract {
     }
 }
 
-pub(crate) fn nft_without_metadata<Q, S>(
+pub(crate) fn nft_without_metadata<Q, S, T>(
     owner_by_id_prefix: Q,
     owner_id: AccountId,
-    enumeration_prefix: Option<S>
+    enumeration_prefix: Option<S>,
+    approval_prefix: Option<T>
 ) -> NonFungibleToken
 where
     Q: IntoStorageKey,
-    S: IntoStorageKey
+    S: IntoStorageKey,
+    T: IntoStorageKey
 {
+    let (approvals_by_id, next_approval_id_by_id) = nft_approvals(approval_prefix);
+
     NonFungibleToken {
         owner_id,
         extra_storage_in_bytes_per_token: 0,
         owner_by_id: TreeMap::new(owner_by_id_prefix),
         token_metadata_by_id: None,
         tokens_per_owner: enumeration_prefix.map(LookupMap::new),
-        approvals_by_id: None,
-        next_approval_id_by_id: None,
+        approvals_by_id,
+        next_approval_id_by_id,
     }
 
     // removed since extra_storage_in_bytes_per_token is not used anywhere in the nft standard
     // this.measure_min_token_storage_cost();
 }
 
+// same storage layout as in NonFungibleToken::new
+#[allow(clippy::type_complexity)]
+pub(crate) fn nft_approvals<T: IntoStorageKey>(
+    approval_prefix: Option<T>
+) -> (Option<LookupMap<TokenId, HashMap<AccountId, u64>>>, Option<LookupMap<TokenId, u64>>) {
+    if let Some(prefix) = approval_prefix {
+        let prefix: Vec<u8> = prefix.into_storage_key();
+        (
+            Some(LookupMap::new(prefix.clone())),
+            Some(LookupMap::new([prefix, "n".into()].concat())),
+        )
+    } else {
+        (None, None)
+    }
+}
+
 #[near_bindgen]
 impl NonFungibleTokenCore for Contract {
+    // only collection items can be transferred
     #[payable]
-    #[allow(unused)]
     fn nft_transfer(
         &mut self,
         receiver_id: AccountId,
@@ -112,11 +166,12 @@ impl NonFungibleTokenCore for Contract {
         approval_id: Option<u64>,
         memo: Option<String>,
     ) {
-        env::panic_str("Not allowed")
+        assert_one_yocto();
+        let sender_id = env::predecessor_account_id();
+        self.internal_transfer_collection_item(&sender_id, &receiver_id, &token_id, approval_id, memo);
     }
 
     #[payable]
-    #[allow(unused)]
     fn nft_transfer_call(
         &mut self,
         receiver_id: AccountId,
@@ -125,22 +180,97 @@ impl NonFungibleTokenCore for Contract {
         memo: Option<String>,
         msg: String,
     ) -> PromiseOrValue<bool> {
-        env::panic_str("Not allowed")
+        assert_one_yocto();
+        require!(env::prepaid_gas() > GAS_FOR_NFT_TRANSFER_CALL, "More gas is required");
+        let sender_id = env::predecessor_account_id();
+        let (old_owner, old_approvals) =
+            self.internal_transfer_collection_item(&sender_id, &receiver_id, &token_id, approval_id, memo);
+
+        ext_nft_receiver::ext(receiver_id.clone())
+            .with_static_gas(env::prepaid_gas().saturating_sub(GAS_FOR_NFT_TRANSFER_CALL))
+            .nft_on_transfer(sender_id, old_owner.clone(), token_id.clone(), msg)
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_RESOLVE_TRANSFER)
+                    .nft_resolve_transfer(old_owner, receiver_id, token_id, old_approvals),
+            )
+            .into()
     }
 
     fn nft_token(&self, token_id: TokenId) -> Option<Token> {
         let owner_id = self.tokens.owner_by_id.get(&token_id).expect("Not found");
         let metadata = self.get_token_metadata(&token_id);
 
+        let approved_account_ids = self.tokens.approvals_by_id.as_ref().map(|approvals_by_id| {
+            approvals_by_id
+                .get(&token_id)
+                .unwrap_or_default()
+        });
+
         Some(Token {
             token_id,
             owner_id,
             metadata: Some(metadata),
-            approved_account_ids: None,
+            approved_account_ids,
         })
 }
 }
 
+// approvals are allowed for collection items only
+#[near_bindgen]
+impl NonFungibleTokenApproval for Contract {
+    #[payable]
+    fn nft_approve(
+        &mut self,
+        token_id: TokenId,
+        account_id: AccountId,
+        msg: Option<String>,
+    ) -> Option<Promise> {
+        require!(is_collection_token_id(&token_id), "Not allowed");
+        self.tokens.nft_approve(token_id, account_id, msg)
+    }
+
+    #[payable]
+    fn nft_revoke(&mut self, token_id: TokenId, account_id: AccountId) {
+        self.tokens.nft_revoke(token_id, account_id)
+    }
+
+    #[payable]
+    fn nft_revoke_all(&mut self, token_id: TokenId) {
+        self.tokens.nft_revoke_all(token_id)
+    }
+
+    fn nft_is_approved(
+        &self,
+        token_id: TokenId,
+        approved_account_id: AccountId,
+        approval_id: Option<u64>,
+    ) -> bool {
+        self.tokens.nft_is_approved(token_id, approved_account_id, approval_id)
+    }
+}
+
+#[near_bindgen]
+impl NonFungibleTokenResolver for Contract {
+    #[private]
+    fn nft_resolve_transfer(
+        &mut self,
+        previous_owner_id: AccountId,
+        receiver_id: AccountId,
+        token_id: TokenId,
+        approved_account_ids: Option<HashMap<AccountId, u64>>,
+    ) -> bool {
+        let is_transferred = self.tokens.nft_resolve_transfer(previous_owner_id.clone(), receiver_id.clone(), token_id.clone(), approved_account_ids);
+
+        // the token was returned to the previous owner
+        if !is_transferred {
+            self.internal_move_collection_item(&receiver_id, &previous_owner_id, &token_id);
+        }
+
+        is_transferred
+    }
+}
+
 #[near_bindgen]
 impl NonFungibleTokenEnumeration for Contract {
     fn nft_total_supply(&self) -> U128 {
@@ -213,6 +343,82 @@ impl NonFungibleTokenEnumeration for Contract {
     }
 }
 
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TokenView {
+    #[serde(flatten)]
+    token: Token,
+    generation: TokenGeneration,
+    // current price
+    price: U128,
+}
+
+impl Contract {
+    fn get_tradable_token_view(&self, owner_id: AccountId, token_id: TokenId) -> TokenView {
+        let token_data = self.get_current_token_data(&token_id);
+        TokenView {
+            token: self.enum_get_token(owner_id, token_id),
+            generation: token_data.generation,
+            price: U128(token_data.price),
+        }
+    }
+}
+
+pub const DEFAULT_PAGE_LIMIT: u64 = 50;
+
+pub(crate) fn page_bounds(from_index: Option<U128>, limit: Option<u64>) -> (usize, usize) {
+    let start_index: u128 = from_index.map(From::from).unwrap_or_default();
+    let limit = limit.unwrap_or(DEFAULT_PAGE_LIMIT) as usize;
+    require!(limit != 0, "Cannot provide limit of 0.");
+    (start_index as usize, limit)
+}
+
+impl Contract {
+    // the page is selected before the views are built
+    fn get_tradable_tokens_page(&self, tokens: impl Iterator<Item = (TokenId, AccountId)>, from_index: Option<U128>, limit: Option<u64>) -> Vec<TokenView> {
+        let (start_index, limit) = page_bounds(from_index, limit);
+
+        tokens
+            .skip(start_index)
+            .take(limit)
+            .map(|(token_id, owner_id)| self.get_tradable_token_view(owner_id, token_id))
+            .collect()
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    // "<ipfs_hash>" tokens only, without collection items
+    pub fn get_tradable_tokens(&self, from_index: Option<U128>, limit: Option<u64>) -> Vec<TokenView> {
+        let tokens = self.tokens
+            .owner_by_id
+            .iter()
+            .filter(|(token_id, _)| !is_collection_token_id(token_id));
+
+        self.get_tradable_tokens_page(tokens, from_index, limit)
+    }
+
+    pub fn get_tradable_tokens_for_owner(&self, account_id: AccountId, from_index: Option<U128>, limit: Option<u64>) -> Vec<TokenView> {
+        let token_set = if let Some(token_set) = self.tokens.tokens_per_owner.as_ref().and_then(|tokens_per_owner| tokens_per_owner.get(&account_id)) {
+            token_set
+        } else {
+            return vec![];
+        };
+
+        let tokens = token_set
+            .iter()
+            .filter(|token_id| !is_collection_token_id(token_id))
+            .map(|token_id| (token_id, account_id.clone()));
+
+        self.get_tradable_tokens_page(tokens, from_index, limit)
+    }
+
+    // "<generation>:<ipfs_hash>" tokens stored in the user collection, same as get_collection
+    pub fn get_collection_paginated(&self, account_id: AccountId, from_index: Option<U128>, limit: Option<u64>) -> Vec<CollectionItemView> {
+        self.get_collection(account_id, from_index, limit).unwrap_or_default()
+    }
+}
+
 #[near_bindgen]
 impl NonFungibleTokenMetadataProvider for Contract {
     fn nft_metadata(&self) -> NFTContractMetadata {
@@ -236,4 +442,8 @@ pub fn parse_token_id (token_id: TokenId) -> (TokenGeneration, TokenId) {
 
 pub fn generate_token_id (generation: &TokenGeneration, token_id: &TokenId) -> TokenId {
     format!("{}:{}", generation, token_id)
-}
\ No newline at end of file
+}
+
+pub fn is_collection_token_id (token_id: &TokenId) -> bool {
+    token_id.contains(':')
+}
@@ -0,0 +1,31 @@
+use crate::*;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Clone, Default)]
+#[borsh(crate = "near_sdk::borsh")]
+#[serde(crate = "near_sdk::serde")]
+pub struct PauseState {
+    pub purchases: bool,
+    pub storage: bool,
+    pub withdrawals: bool,
+    pub collection: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub enum PausableOperation {
+    Purchases,
+    Storage,
+    Withdrawals,
+    Collection,
+}
+
+impl PauseState {
+    pub fn set(&mut self, operation: PausableOperation, paused: bool) {
+        match operation {
+            PausableOperation::Purchases => self.purchases = paused,
+            PausableOperation::Storage => self.storage = paused,
+            PausableOperation::Withdrawals => self.withdrawals = paused,
+            PausableOperation::Collection => self.collection = paused,
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use crate::*;
+use near_sdk::assert_one_yocto;
+use std::collections::HashMap;
+
+// NEP-199
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Payout {
+    pub payout: HashMap<AccountId, U128>,
+}
+
+impl Contract {
+    // royalty to the treasury, the rest to the token owner
+    fn internal_get_payout(&self, owner_id: &AccountId, balance: Balance, max_len_payout: Option<u32>) -> Payout {
+        let royalty = self.royalty_fee.multiply(balance);
+
+        let mut payout: HashMap<AccountId, U128> = HashMap::new();
+        if royalty > 0 {
+            payout.insert(self.treasury_id.clone(), U128(royalty));
+        }
+        let owner_payout = payout.get(owner_id).map(|amount| amount.0).unwrap_or(0) + balance - royalty;
+        payout.insert(owner_id.clone(), U128(owner_payout));
+
+        if let Some(max_len_payout) = max_len_payout {
+            assert!(payout.len() <= max_len_payout as usize, "Too many payout receivers");
+        }
+
+        Payout { payout }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    // payouts are available for collection items only
+    pub fn nft_payout(&self, token_id: TokenId, balance: U128, max_len_payout: Option<u32>) -> Payout {
+        assert!(is_collection_token_id(&token_id), "Not allowed");
+        let owner_id = self.tokens.owner_by_id.get(&token_id).expect("Not found");
+        self.internal_get_payout(&owner_id, balance.0, max_len_payout)
+    }
+
+    #[payable]
+    pub fn nft_transfer_payout(
+        &mut self,
+        receiver_id: AccountId,
+        token_id: TokenId,
+        approval_id: Option<u64>,
+        memo: Option<String>,
+        balance: U128,
+        max_len_payout: Option<u32>,
+    ) -> Payout {
+        assert_one_yocto();
+        let sender_id = env::predecessor_account_id();
+        let (previous_owner_id, _) = self.internal_transfer_collection_item(&sender_id, &receiver_id, &token_id, approval_id, memo);
+        self.internal_get_payout(&previous_owner_id, balance.0, max_len_payout)
+    }
+}
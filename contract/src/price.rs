@@ -0,0 +1,92 @@
+use crate::*;
+use crate::utils::U256;
+
+// how the price grows on every resale
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[borsh(crate = "near_sdk::borsh")]
+#[serde(crate = "near_sdk::serde")]
+pub enum PriceCurve {
+    // old_price + fee * old_price
+    FixedPercentage { fee: FeeFraction },
+    // old_price + step
+    LinearStep { step: U128 },
+    // old_price + fee * old_price, but not more than max_price
+    CappedPercentage { fee: FeeFraction, max_price: U128 },
+    // prices[0] for generation 1, prices[1] for generation 2, etc. The last price is used for later generations
+    Table { prices: Vec<U128> },
+}
+
+impl PriceCurve {
+    pub fn assert_valid(&self) {
+        match self {
+            PriceCurve::FixedPercentage { fee } => fee.assert_valid(),
+            PriceCurve::LinearStep { .. } => {}
+            PriceCurve::CappedPercentage { fee, .. } => fee.assert_valid(),
+            PriceCurve::Table { prices } => {
+                assert!(!prices.is_empty(), "Empty price table");
+                assert!(prices.windows(2).all(|pair| pair[0].0 <= pair[1].0), "Prices in the table must not decrease");
+            }
+        }
+    }
+
+    pub fn get_increase_fee(&self) -> Option<FeeFraction> {
+        match self {
+            PriceCurve::FixedPercentage { fee } | PriceCurve::CappedPercentage { fee, .. } => Some(fee.clone()),
+            PriceCurve::LinearStep { .. } | PriceCurve::Table { .. } => None,
+        }
+    }
+
+    // price of the token of a given generation, never lower than the old price
+    pub fn next_price(&self, old_price: Balance, generation: TokenGeneration) -> Balance {
+        let new_price = match self {
+            PriceCurve::FixedPercentage { fee } => old_price + fee.multiply(old_price),
+            PriceCurve::LinearStep { step } => old_price + step.0,
+            PriceCurve::CappedPercentage { fee, max_price } => {
+                std::cmp::min(old_price + fee.multiply(old_price), max_price.0)
+            }
+            PriceCurve::Table { prices } => {
+                let index = std::cmp::min(generation.saturating_sub(1) as usize, prices.len() - 1);
+                prices[index].0
+            }
+        };
+
+        std::cmp::max(new_price, old_price)
+    }
+}
+
+// dutch auction style decrease of the price of tokens without sales
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[borsh(crate = "near_sdk::borsh")]
+#[serde(crate = "near_sdk::serde")]
+pub struct PriceDecay {
+    // the price doesn't change during this period after the last sale, nanoseconds
+    pub idle_period: U64,
+    // time to reach the floor price after the idle period, nanoseconds
+    pub decay_period: U64,
+    // the price never goes below the floor price and the min mint price of the token
+    pub floor_price: U128,
+}
+
+impl PriceDecay {
+    pub fn assert_valid(&self) {
+        assert!(self.decay_period.0 > 0, "Decay period must be a positive number");
+    }
+
+    // linear decrease from the sale price to the floor
+    // idle_since - the last sale or the time the decay was enabled if the token was sold before
+    pub fn decayed_price(&self, price: Balance, min_price: Balance, idle_since: Timestamp, timestamp: Timestamp) -> Balance {
+        let decay_start = idle_since + self.idle_period.0;
+        if timestamp <= decay_start {
+            return price;
+        }
+
+        let floor_price = std::cmp::min(std::cmp::max(self.floor_price.0, min_price), price);
+        let elapsed = timestamp - decay_start;
+        if elapsed >= self.decay_period.0 {
+            return floor_price;
+        }
+
+        let decrease = U256::from(price - floor_price) * U256::from(elapsed) / U256::from(self.decay_period.0);
+        price - decrease.as_u128()
+    }
+}
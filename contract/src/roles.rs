@@ -0,0 +1,89 @@
+use crate::*;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[borsh(crate = "near_sdk::borsh")]
+#[serde(crate = "near_sdk::serde")]
+pub enum Role {
+    // prices, fees, token economics, payouts
+    FeeManager,
+    // signer keys and message domain
+    KeyManager,
+    // storage limits and packages, history size
+    StorageManager,
+    // removal of user collection items
+    Moderator,
+    // pause switches
+    Pauser,
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn grant_role(&mut self, account_id: AccountId, role: Role) {
+        self.assert_owner();
+        let mut roles = self.roles.get(&account_id).unwrap_or_default();
+        if !roles.contains(&role) {
+            roles.push(role);
+            self.roles.insert(&account_id, &roles);
+        }
+    }
+
+    pub fn revoke_role(&mut self, account_id: AccountId, role: Role) {
+        self.assert_owner();
+        let mut roles = self.roles.get(&account_id).expect("Account has no roles");
+        let len = roles.len();
+        roles.retain(|account_role| account_role != &role);
+        assert_ne!(len, roles.len(), "Role not found");
+
+        if roles.is_empty() {
+            self.roles.remove(&account_id);
+        } else {
+            self.roles.insert(&account_id, &roles);
+        }
+    }
+
+    pub fn get_roles(&self) -> Vec<(AccountId, Vec<Role>)> {
+        self.roles.to_vec()
+    }
+
+    pub fn get_owner_id(&self) -> AccountId {
+        self.owner_id.clone()
+    }
+
+    // None cancels the proposal
+    pub fn propose_owner(&mut self, proposed_owner_id: Option<AccountId>) {
+        self.assert_owner();
+        self.proposed_owner_id = proposed_owner_id;
+    }
+
+    pub fn get_proposed_owner_id(&self) -> Option<AccountId> {
+        self.proposed_owner_id.clone()
+    }
+
+    pub fn accept_ownership(&mut self) {
+        let account_id = env::predecessor_account_id();
+        assert_eq!(self.proposed_owner_id.as_ref(), Some(&account_id), "Not a proposed owner");
+
+        self.proposed_owner_id = None;
+        self.tokens.owner_id = account_id.clone();
+        self.owner_id = account_id;
+    }
+
+    pub fn set_treasury_id(&mut self, treasury_id: AccountId) {
+        self.assert_owner();
+        self.treasury_id = treasury_id;
+    }
+
+    pub fn get_treasury_id(&self) -> AccountId {
+        self.treasury_id.clone()
+    }
+}
+
+impl Contract {
+    // the owner has every role
+    pub fn assert_role(&self, role: Role) {
+        let account_id = env::predecessor_account_id();
+        if account_id != self.owner_id {
+            assert!(self.roles.get(&account_id).map_or(false, |roles| roles.contains(&role)), "Not allowed");
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use crate::*;
+use near_sdk::json_types::I128;
+
+// accounts kept in the leaderboard of every metric
+pub const LEADERBOARD_SIZE: usize = 50;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Clone, Default)]
+#[borsh(crate = "near_sdk::borsh")]
+#[serde(crate = "near_sdk::serde")]
+pub struct AccountStats {
+    pub volume_bought: U128,
+    pub volume_sold: U128,
+    // seller payouts minus the prices the account paid for sold tokens
+    pub profit: I128,
+    pub referral_income: U128,
+    // part of purchase prices paid as referral and system fees
+    pub fees_paid: U128,
+    pub purchases: u64,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Clone, Default)]
+#[borsh(crate = "near_sdk::borsh")]
+#[serde(crate = "near_sdk::serde")]
+pub struct MarketStats {
+    pub total_volume: U128,
+    pub total_system_fees: U128,
+    pub total_referral_fees: U128,
+    // tradable tokens, without collection items
+    pub distinct_tokens: u64,
+    pub highest_price: U128,
+    pub highest_generation: TokenGeneration,
+    pub collection_items: u64,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[borsh(crate = "near_sdk::borsh")]
+#[serde(crate = "near_sdk::serde")]
+pub enum StatsMetric {
+    VolumeBought,
+    VolumeSold,
+    Profit,
+    ReferralIncome,
+    FeesPaid,
+    Purchases,
+}
+
+impl AccountStats {
+    pub fn get_metric(&self, metric: &StatsMetric) -> i128 {
+        match metric {
+            StatsMetric::VolumeBought => self.volume_bought.0 as i128,
+            StatsMetric::VolumeSold => self.volume_sold.0 as i128,
+            StatsMetric::Profit => self.profit.0,
+            StatsMetric::ReferralIncome => self.referral_income.0 as i128,
+            StatsMetric::FeesPaid => self.fees_paid.0 as i128,
+            StatsMetric::Purchases => self.purchases as i128,
+        }
+    }
+}
+
+impl Contract {
+    pub(crate) fn internal_get_account_stats(&self, account_id: &AccountId) -> AccountStats {
+        self.account_stats.get(account_id).unwrap_or_default()
+    }
+
+    // metrics - metrics changed by the update
+    fn internal_update_account_stats<F: FnOnce(&mut AccountStats)>(&mut self, account_id: &AccountId, metrics: &[StatsMetric], update: F) {
+        let mut stats = self.internal_get_account_stats(account_id);
+        update(&mut stats);
+        self.account_stats.insert(account_id, &stats);
+
+        for metric in metrics {
+            self.internal_update_leaderboard(account_id, *metric, stats.get_metric(metric));
+        }
+    }
+
+    // sorted by value, the account is moved to its new position or added if the value is high enough
+    fn internal_update_leaderboard(&mut self, account_id: &AccountId, metric: StatsMetric, value: i128) {
+        let mut leaderboard = self.leaderboards.get(&metric).cloned().unwrap_or_default();
+        leaderboard.retain(|(leader_id, _)| leader_id != account_id);
+
+        let index = leaderboard.iter().position(|(_, leader_value)| *leader_value < value).unwrap_or(leaderboard.len());
+        if index < LEADERBOARD_SIZE {
+            leaderboard.insert(index, (account_id.clone(), value));
+            leaderboard.truncate(LEADERBOARD_SIZE);
+        }
+        self.leaderboards.insert(metric, leaderboard);
+    }
+
+    pub(crate) fn internal_add_purchase_stats(&mut self, account_id: &AccountId, price: Balance, fees: Balance) {
+        self.internal_update_account_stats(account_id, &[StatsMetric::VolumeBought, StatsMetric::FeesPaid, StatsMetric::Purchases], |stats| {
+            stats.volume_bought = U128(stats.volume_bought.0 + price);
+            stats.fees_paid = U128(stats.fees_paid.0 + fees);
+            stats.purchases += 1;
+        });
+    }
+
+    // purchase_price - price the seller paid for the token
+    pub(crate) fn internal_add_sale_stats(&mut self, account_id: &AccountId, price: Balance, seller_payout: Balance, purchase_price: Balance) {
+        self.internal_update_account_stats(account_id, &[StatsMetric::VolumeSold, StatsMetric::Profit], |stats| {
+            stats.volume_sold = U128(stats.volume_sold.0 + price);
+            stats.profit = I128(stats.profit.0 + seller_payout as i128 - purchase_price as i128);
+        });
+    }
+
+    pub(crate) fn internal_add_referral_stats(&mut self, account_id: &AccountId, referral_fee: Balance) {
+        self.internal_update_account_stats(account_id, &[StatsMetric::ReferralIncome], |stats| {
+            stats.referral_income = U128(stats.referral_income.0 + referral_fee);
+        });
+    }
+}
+
+impl MarketStats {
+    pub fn add_sale(&mut self, price: Balance, generation: TokenGeneration) {
+        self.total_volume = U128(self.total_volume.0 + price);
+        self.highest_price = U128(std::cmp::max(self.highest_price.0, price));
+        self.highest_generation = std::cmp::max(self.highest_generation, generation);
+    }
+
+    pub fn add_fees(&mut self, system_fee: Balance, referral_fees: Balance) {
+        self.total_system_fees = U128(self.total_system_fees.0 + system_fee);
+        self.total_referral_fees = U128(self.total_referral_fees.0 + referral_fees);
+    }
+}
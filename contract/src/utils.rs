@@ -1,5 +1,6 @@
 use crate::*;
 use crate::market::FREE_STORAGE_SIZE;
+use near_sdk::require;
 
 #[near_bindgen]
 impl Contract {
@@ -11,9 +12,25 @@ impl Contract {
         self.get_user_collection_items(&account_id)
     }
 
-    pub fn get_collection(&self, account_id: AccountId) -> Option<Vec<CollectionItem>> {
+    // items in the order of addition, a removed item is replaced by the last one
+    pub fn get_collection(&self, account_id: AccountId, from_index: Option<U128>, limit: Option<u64>) -> Option<Vec<CollectionItemView>> {
         if let Some(collection) = self.get_user_collection(&account_id){
-            Some(collection.to_vec())
+            let (start_index, limit) = page_bounds(from_index, limit);
+
+            Some(collection
+                .as_vector()
+                .iter()
+                .skip(start_index)
+                .take(limit)
+                .map(|collection_item| {
+                    let full_token_id = generate_token_id(&collection_item.generation, &collection_item.token_id);
+                    CollectionItemView {
+                        metadata: self.get_token_metadata(&full_token_id),
+                        full_token_id,
+                        item: collection_item,
+                    }
+                })
+                .collect())
         }
         else {
             None
@@ -28,62 +45,200 @@ impl Contract {
         self.is_store_user_tokens.get(&account_id).unwrap_or(&false).clone()
     }
 
-    pub fn set_min_mint_price(&mut self, min_mint_price: U128) {
-        self.assert_owner();
-        self.min_mint_price = min_mint_price.0;
+    // takes effect after the timelock period
+    pub fn set_min_mint_price(&mut self, min_mint_price: U128) -> PendingChange {
+        self.assert_role(Role::FeeManager);
+        self.internal_queue_change(ParameterChange::Global(EconomicsOverride { min_mint_price: Some(min_mint_price), ..Default::default() }))
     }
 
     pub fn get_min_mint_price(&self) -> U128{
-        U128::from(self.min_mint_price)
+        self.get_effective_global_economics().min_mint_price
     }
 
-    pub fn get_public_key(&mut self) -> String {
-        self.public_key.clone()
+    pub fn get_signer_keys(&self) -> Vec<(String, SignerKey)> {
+        self.signer_keys.to_vec()
     }
 
-    pub fn set_public_key(&mut self, public_key: String) {
-        self.assert_owner();
-        self.public_key = public_key;
+    pub fn get_message_domain(&self) -> String {
+        self.message_domain.clone()
     }
 
-    pub fn set_mint_price_increase_fee(&mut self, mint_price_increase_fee: FeeFraction) {
-        self.assert_owner();
-        self.mint_price_increase_fee = mint_price_increase_fee;
+    pub fn set_message_domain(&mut self, message_domain: String) {
+        self.assert_role(Role::KeyManager);
+        self.message_domain = message_domain;
     }
 
-    pub fn set_seller_fee(&mut self, seller_fee: FeeFraction) {
-        self.assert_owner();
-        assert_fees_overflow(vec![&seller_fee, &self.referral_1_fee, &self.referral_2_fee]);
-        self.seller_fee = seller_fee;
+    pub fn get_accept_legacy_messages(&self) -> bool {
+        self.accept_legacy_messages
     }
 
-    pub fn set_referral_fee(&mut self, referral_1_fee: FeeFraction, referral_2_fee: FeeFraction) {
-        self.assert_owner();
-        assert_fees_overflow(vec![&self.seller_fee, &referral_1_fee, &referral_2_fee]);
-        self.referral_1_fee = referral_1_fee;
-        self.referral_2_fee = referral_2_fee;
+    pub fn set_accept_legacy_messages(&mut self, accept_legacy_messages: bool) {
+        self.assert_role(Role::KeyManager);
+        self.accept_legacy_messages = accept_legacy_messages;
+    }
+
+    pub fn add_signer_key(&mut self, public_key: String, label: String, valid_until: Option<U64>) {
+        self.assert_role(Role::KeyManager);
+        assert_valid_public_key(&public_key);
+        self.signer_keys.insert(&public_key, &SignerKey { label, valid_until });
+    }
+
+    pub fn revoke_signer_key(&mut self, public_key: String) {
+        self.assert_role(Role::KeyManager);
+        self.signer_keys.remove(&public_key).expect("Signer key not found");
+    }
+
+    // takes effect after the timelock period
+    pub fn set_mint_price_increase_fee(&mut self, mint_price_increase_fee: FeeFraction) -> PendingChange {
+        self.assert_role(Role::FeeManager);
+        mint_price_increase_fee.assert_valid();
+        self.internal_queue_change(ParameterChange::Global(EconomicsOverride { price_curve: Some(PriceCurve::FixedPercentage { fee: mint_price_increase_fee }), ..Default::default() }))
+    }
+
+    // takes effect after the timelock period
+    pub fn set_price_curve(&mut self, price_curve: PriceCurve) -> PendingChange {
+        self.assert_role(Role::FeeManager);
+        price_curve.assert_valid();
+        self.internal_queue_change(ParameterChange::Global(EconomicsOverride { price_curve: Some(price_curve), ..Default::default() }))
+    }
+
+    pub fn get_price_curve(&self) -> PriceCurve {
+        self.get_effective_global_economics().price_curve
+    }
+
+    // takes effect after the timelock period
+    pub fn set_price_decay(&mut self, price_decay: Option<PriceDecay>) -> PendingChange {
+        self.assert_role(Role::FeeManager);
+        self.internal_queue_change(ParameterChange::PriceDecay(price_decay))
+    }
+
+    pub fn get_price_decay(&self) -> Option<PriceDecay> {
+        self.get_effective_price_decay().map(|(price_decay, _)| price_decay)
+    }
+
+    // tokens can't be resold during this period after the sale, nanoseconds
+    pub fn set_sale_cooldown(&mut self, sale_cooldown: U64) {
+        self.assert_role(Role::FeeManager);
+        self.sale_cooldown = sale_cooldown.0;
+    }
+
+    pub fn get_sale_cooldown(&self) -> U64 {
+        U64(self.sale_cooldown)
+    }
+
+    // takes effect after the timelock period
+    pub fn set_seller_fee(&mut self, seller_fee: FeeFraction) -> PendingChange {
+        self.assert_role(Role::FeeManager);
+        seller_fee.assert_valid();
+        self.internal_queue_change(ParameterChange::Global(EconomicsOverride { seller_fee: Some(seller_fee), ..Default::default() }))
+    }
+
+    // takes effect after the timelock period
+    pub fn set_referral_fee(&mut self, referral_1_fee: FeeFraction, referral_2_fee: FeeFraction) -> PendingChange {
+        self.assert_role(Role::FeeManager);
+        referral_1_fee.assert_valid();
+        referral_2_fee.assert_valid();
+        self.internal_queue_change(ParameterChange::Global(EconomicsOverride { referral_1_fee: Some(referral_1_fee), referral_2_fee: Some(referral_2_fee), ..Default::default() }))
     }
 
     pub fn set_max_storage_size(&mut self, max_storage_size: StorageSize) {
-        self.assert_owner();
+        self.assert_role(Role::StorageManager);
         self.max_storage_size = max_storage_size;
     }
 
     pub fn remove_user_collection_item_for_user(&mut self, account_id: AccountId, generation: TokenGeneration, token_id: TokenId) {
-        self.assert_owner();
+        self.assert_role(Role::Moderator);
         self.internal_remove_user_collection_item(account_id, generation, token_id, false);
     }
 
+    pub fn set_batch_payouts(&mut self, batch_payouts: bool) {
+        self.assert_role(Role::FeeManager);
+        self.batch_payouts = batch_payouts;
+    }
+
+    pub fn get_batch_payouts(&self) -> bool {
+        self.batch_payouts
+    }
+
+    pub fn get_pending_payouts_count(&self) -> u64 {
+        self.payout_queue.len()
+    }
+
+    // effective prices and fees of the token after group and token overrides
+    pub fn get_token_economics(&self, token_id: TokenId) -> TokenEconomics {
+        self.internal_get_token_economics(&token_id)
+    }
+
+    // effective overrides, including the changes which reached effective_at
+    pub fn get_group_economics(&self) -> Vec<(String, EconomicsOverride)> {
+        let mut group_economics = self.group_economics.to_vec();
+        for change in self.get_effective_changes() {
+            if let ParameterChange::GroupEconomics { group, economics } = change {
+                group_economics.retain(|(existing_group, _)| existing_group != group);
+                if let Some(economics) = economics {
+                    group_economics.push((group.clone(), economics.clone()));
+                }
+            }
+        }
+        group_economics
+    }
+
+    // takes effect after the timelock period
+    pub fn set_group_economics(&mut self, group: String, economics: EconomicsOverride) -> PendingChange {
+        self.assert_role(Role::FeeManager);
+        self.internal_queue_change(ParameterChange::GroupEconomics { group, economics: Some(economics) })
+    }
+
+    // takes effect after the timelock period
+    pub fn remove_group_economics(&mut self, group: String) -> PendingChange {
+        self.assert_role(Role::FeeManager);
+        assert!(self.get_group_economics().iter().any(|(existing_group, _)| existing_group == &group), "Group not found");
+        self.internal_queue_change(ParameterChange::GroupEconomics { group, economics: None })
+    }
+
+    // tokens without a group use the global economics, takes effect after the timelock period
+    pub fn set_token_group(&mut self, token_id: TokenId, group: Option<String>) -> PendingChange {
+        self.assert_role(Role::FeeManager);
+        self.internal_queue_change(ParameterChange::TokenGroup { token_id, group })
+    }
+
+    // takes effect after the timelock period
+    pub fn set_token_economics(&mut self, token_id: TokenId, economics: Option<EconomicsOverride>) -> PendingChange {
+        self.assert_role(Role::FeeManager);
+        self.internal_queue_change(ParameterChange::TokenEconomics { token_id, economics })
+    }
+
+    pub fn set_paused(&mut self, operation: PausableOperation, paused: bool) {
+        self.assert_role(Role::Pauser);
+        self.pause_state.set(operation, paused);
+    }
+
+    pub fn get_pause_state(&self) -> PauseState {
+        self.pause_state.clone()
+    }
+
     pub fn get_max_storage_size(&self) -> StorageSize {
         self.max_storage_size
     }
 
-    pub fn get_fees(&self) -> (FeeFraction, FeeFraction, FeeFraction, FeeFraction) {
-        (self.mint_price_increase_fee.clone(), self.seller_fee.clone(), self.referral_1_fee.clone(), self.referral_2_fee.clone())
+    pub fn set_royalty_fee(&mut self, royalty_fee: FeeFraction) {
+        self.assert_role(Role::FeeManager);
+        royalty_fee.assert_valid();
+        self.royalty_fee = royalty_fee;
+    }
+
+    pub fn get_royalty_fee(&self) -> FeeFraction {
+        self.royalty_fee.clone()
+    }
+
+    // mint_price_increase_fee is null if the price curve is not a percentage
+    pub fn get_fees(&self) -> (Option<FeeFraction>, FeeFraction, FeeFraction, FeeFraction) {
+        let economics = self.get_effective_global_economics();
+        (economics.price_curve.get_increase_fee(), economics.seller_fee, economics.referral_1_fee, economics.referral_2_fee)
     }
 
     pub fn add_storage_package(&mut self, storage_size: StorageSize, price: U128) {
-        self.assert_owner();
+        self.assert_role(Role::StorageManager);
         let next_index = self.storage_packages.len() + 1;
         self.storage_packages.insert(&next_index, &StoragePackage {
             storage_size,
@@ -94,7 +249,7 @@ impl Contract {
     #[allow(unused_variables)]
     pub fn remove_storage_package(&mut self, index: StoragePackageIndex) {
         panic!("Protected");
-        // self.assert_owner();
+        // self.assert_role(Role::StorageManager);
         // self.storage_packages.remove(&index);
     }
 
@@ -115,10 +270,61 @@ impl Contract {
     }
 
     pub fn remove_user_collection_item(&mut self, generation: TokenGeneration, token_id: TokenId) {
+        assert!(!self.pause_state.collection, "Collection removals are paused");
         let account_id = env::predecessor_account_id();
         self.internal_remove_user_collection_item(account_id, generation, token_id, true);
     }
 
+    // last sales of the token, oldest first
+    pub fn get_token_history(&self, token_id: TokenId, from_index: Option<U128>, limit: Option<u64>) -> Vec<SaleRecord> {
+        let history = if let Some(history) = self.token_history.get(&token_id) {
+            history
+        } else {
+            return vec![];
+        };
+
+        let (start_index, limit) = page_bounds(from_index, limit);
+        history
+            .iter()
+            .skip(start_index)
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    // 0 disables the history, old records are removed on the next sale of the token
+    pub fn set_max_history_per_token(&mut self, max_history_per_token: u32) {
+        self.assert_role(Role::StorageManager);
+        self.max_history_per_token = max_history_per_token;
+    }
+
+    pub fn get_max_history_per_token(&self) -> u32 {
+        self.max_history_per_token
+    }
+
+    pub fn get_account_stats(&self, account_id: AccountId) -> AccountStats {
+        self.internal_get_account_stats(&account_id)
+    }
+
+    pub fn get_market_stats(&self) -> MarketStats {
+        self.market_stats.clone()
+    }
+
+    // top accounts by a given metric, at most LEADERBOARD_SIZE
+    pub fn get_leaderboard(&self, metric: StatsMetric, limit: Option<u64>) -> Vec<(AccountId, AccountStats)> {
+        let limit = limit.map(|v| v as usize).unwrap_or(10);
+        require!(limit != 0, "Cannot provide limit of 0.");
+
+        self.leaderboards
+            .get(&metric)
+            .map(|leaderboard| leaderboard
+                .iter()
+                .take(limit)
+                .map(|(account_id, _)| (account_id.clone(), self.internal_get_account_stats(account_id)))
+                .collect())
+            .unwrap_or_default()
+    }
+
     // returns [token, [generation, price]]
     pub fn get_token(&self, token_id: TokenId) -> (Option<Token>, Option<(TokenGeneration, U128)>) {
         if let Some(token) = self.tokens.nft_token(token_id.clone()) {
@@ -128,7 +334,7 @@ impl Contract {
                 return (Some(token), None);
             }
 
-            let token_data = self.get_token_data(&token_id);
+            let token_data = self.get_current_token_data(&token_id);
             (
                 Some(token),
                 Some((token_data.generation, U128::from(token_data.price)))
@@ -138,23 +344,22 @@ impl Contract {
         }
     }
 
-    // returns [token, next_price, seller_collection_items, seller_total_items, seller_is_store_tokens]
-    pub fn get_token_for_sale(&self, token_id: TokenId) -> Option<(Token, U128, StorageSize, StorageSize, bool)> {
+    // returns [token, next_price, seller_collection_items, seller_total_items, seller_is_store_tokens, available_at]
+    pub fn get_token_for_sale(&self, token_id: TokenId) -> Option<(Token, U128, StorageSize, StorageSize, bool, U64)> {
         // token from user collection
         if token_id.contains(':') {
             return None;
         }
 
         if let Some(token) = self.tokens.nft_token(token_id.clone()) {
-            let old_price: Balance = self.get_token_price(&token_id);
-
-            let price_increase = self.mint_price_increase_fee.multiply(old_price);
+            let token_data = self.get_current_token_data(&token_id);
+            let next_price: Balance = self.get_next_price(&token_id, &token_data);
 
             let seller_collection_items =  self.get_user_collection_items(&token.owner_id);
             let seller_total_items = self.internal_total_supply_by_user(&token.owner_id);
             let seller_is_store_tokens = *self.is_store_user_tokens.get(&token.owner_id).unwrap_or(&false);
 
-            Some((token, U128::from(old_price + price_increase), seller_collection_items, seller_total_items, seller_is_store_tokens))
+            Some((token, U128::from(next_price), seller_collection_items, seller_total_items, seller_is_store_tokens, U64(token_data.available_at)))
         } else {
             None
         }
@@ -167,6 +372,34 @@ impl Contract {
     }
 }
 
+impl SignerKey {
+    pub fn is_active(&self, timestamp: Timestamp) -> bool {
+        self.valid_until.map_or(true, |valid_until| timestamp <= valid_until.0)
+    }
+}
+
+pub fn decode_public_key(public_key: &str) -> [u8; 32] {
+    let mut pk = [0u8; 32];
+    let v = hex::decode(public_key).expect("Failed to decode public key");
+    assert_eq!(pk.len(), v.len(), "Invalid key length");
+    pk.copy_from_slice(&v);
+    pk
+}
+
+pub fn assert_valid_public_key(public_key: &str) {
+    decode_public_key(public_key);
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CollectionItemView {
+    #[serde(flatten)]
+    item: CollectionItem,
+    // "<generation>:<ipfs_hash>"
+    full_token_id: TokenId,
+    metadata: TokenMetadata,
+}
+
 #[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
 #[borsh(crate = "near_sdk::borsh")]
 #[serde(crate = "near_sdk::serde")]
@@ -190,13 +423,17 @@ impl FeeFraction {
 }
 
 // verify if sum of all fee fractions is not exceeded 100%
-pub fn assert_fees_overflow(items: Vec<&FeeFraction>) {
+pub fn fees_overflow(items: Vec<&FeeFraction>) -> bool {
     let base_amount: Balance = 1000000;
     let mut sum_all_fees: Balance = 0;
     for fee in items {
         sum_all_fees += fee.multiply(base_amount)
     }
-    assert!(sum_all_fees <= base_amount, "Fees overflow");
+    sum_all_fees > base_amount
+}
+
+pub fn assert_fees_overflow(items: Vec<&FeeFraction>) {
+    assert!(!fees_overflow(items), "Fees overflow");
 }
 
 use uint::construct_uint;